@@ -6,11 +6,127 @@
 
 .global start
 .global abort
+.global irq_entry
+.global task_yield
+.global prefetch_abort_entry
+.global data_abort_entry
+.global swi_entry
+.global boot_regs
 
 .type start, %function
 
 start:
+    ldr r3, =boot_regs
+    stmia r3, {r0-r2}           @ r1 = machine type, r2 = ATAG list or DTB
     mov sp, 0x18000
     bl main
+idle:
+    b idle                      @ the boot task idles once main returns
+
 abort:
-    b .
+    b abort_panic               @ core::fail::abort; reports and halts
+
+/*
+ * Exception entry and exit.
+ *
+ * The entry sequence moves to supervisor mode and pushes the interrupted
+ * state onto the supervisor stack, lowest address first:
+ *
+ *     usr_sp usr_lr spsr r0 ... r12 lr pc
+ *
+ * (see cpu::interrupt::Context). The handler gets a pointer to this frame
+ * in r0 and returns the frame to resume in r0, which need not be the one
+ * it was given.
+ */
+.macro EXCEPTION_ENTRY adjust
+    sub lr, lr, #\adjust        @ lr = address to resume at
+    stmdb sp, {r0-r2}           @ scratch below the exception mode stack
+    mov r0, sp
+    mov r1, lr
+    mrs r2, spsr
+    msr cpsr_c, #0xD3           @ supervisor mode, IRQ and FIQ masked
+    stmfd sp!, {r1}             @ pc
+    stmfd sp!, {r3-r12, lr}
+    ldmdb r0, {r3-r5}           @ original r0-r2
+    stmfd sp!, {r3-r5}
+    stmfd sp!, {r2}             @ spsr
+    sub sp, sp, #8
+    mov r0, sp
+    stmia r0, {sp, lr}^         @ banked user sp and lr
+    nop
+.endm
+
+.macro EXCEPTION_RETURN
+    mov sp, r0
+    ldmia r0, {sp, lr}^
+    nop
+    add sp, sp, #8
+    ldmfd sp!, {r0}
+    msr spsr_cxsf, r0
+    ldmfd sp!, {r0-r12, lr, pc}^
+.endm
+
+.type irq_entry, %function
+
+irq_entry:
+    EXCEPTION_ENTRY 4
+    bl irq_dispatch
+    EXCEPTION_RETURN
+
+/*
+ * A swi already runs in supervisor mode with lr pointing after the
+ * instruction, so only the frame needs building.
+ */
+.type swi_entry, %function
+
+swi_entry:
+    stmfd sp!, {lr}             @ pc
+    stmfd sp!, {r0-r12, lr}
+    mrs r0, spsr
+    stmfd sp!, {r0}
+    sub sp, sp, #8
+    mov r0, sp
+    stmia r0, {sp, lr}^
+    nop
+    bl swi_dispatch
+    EXCEPTION_RETURN
+
+/* Both aborts leave pc at the faulting instruction */
+.type prefetch_abort_entry, %function
+
+prefetch_abort_entry:
+    EXCEPTION_ENTRY 4
+    bl prefetch_abort
+    EXCEPTION_RETURN
+
+.type data_abort_entry, %function
+
+data_abort_entry:
+    EXCEPTION_ENTRY 8
+    bl data_abort
+    EXCEPTION_RETURN
+
+/*
+ * Voluntary task switch, called like a function from supervisor mode.
+ * Builds the same frame as an exception so the task can later be resumed
+ * by EXCEPTION_RETURN from either path.
+ */
+.type task_yield, %function
+
+task_yield:
+    stmfd sp!, {lr}             @ pc: return to the caller
+    stmfd sp!, {r0-r12, lr}
+    mrs r0, cpsr
+    stmfd sp!, {r0}             @ spsr: resume with the caller's mode and mask
+    msr cpsr_c, #0xD3
+    sub sp, sp, #8
+    mov r0, sp
+    stmia r0, {sp, lr}^
+    nop
+    bl task_yield_switch
+    EXCEPTION_RETURN
+
+.data
+
+boot_regs:
+    .word 0, 0, 0
@@ -0,0 +1,75 @@
+/* cpu::fault */
+/* Prefetch and data abort handlers */
+
+use platform::cpu::interrupt::Context;
+use kernel::panic;
+use kernel::panic::{puts, put_hex, put_dec};
+use kernel::task;
+
+static CPSR_I: u32 = 1 << 7;
+
+/// Meaning of the status field of the fault status registers [1]
+// [1] http://infocenter.arm.com/help/topic/com.arm.doc.ddi0198e/I1039311.html
+pub fn describe(status: u32) -> &'static str {
+    match status & 0xF {
+        0b0001 | 0b0011 => "alignment",
+        0b0100 => "external abort on linefetch (section)",
+        0b0110 => "external abort on linefetch (page)",
+        0b0101 => "translation (section)",
+        0b0111 => "translation (page)",
+        0b1001 => "domain (section)",
+        0b1011 => "domain (page)",
+        0b1101 => "permission (section)",
+        0b1111 => "permission (page)",
+        0b1000 => "external abort (section)",
+        0b1010 => "external abort (page)",
+        0b1100 => "external abort on translation (first level)",
+        0b1110 => "external abort on translation (second level)",
+        _ => "unknown"
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn prefetch_abort(ctx: *mut Context) -> *mut Context {
+    let ifsr: u32;
+    asm!("mrc p15, 0, $0, c5, c0, 1" : "=r"(ifsr));
+    // FAR is not updated by prefetch aborts; the faulting address is pc
+    report("prefetch abort", ifsr, (*ctx).pc);
+    recover(ctx)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn data_abort(ctx: *mut Context) -> *mut Context {
+    let dfsr: u32;
+    let far: u32;
+    asm!("mrc p15, 0, $0, c5, c0, 0" : "=r"(dfsr));
+    asm!("mrc p15, 0, $0, c6, c0, 0" : "=r"(far));
+    report("data abort", dfsr, far);
+    recover(ctx)
+}
+
+/// A task can be killed off; a fault in the boot task or with interrupts
+/// masked means the kernel itself is broken.
+unsafe fn recover(ctx: *mut Context) -> *mut Context {
+    if task::current_id() != 0 && (*ctx).spsr & CPSR_I == 0 {
+        panic::dump(&*ctx, panic::saved_sp(&*ctx));
+        puts("killing task ");
+        put_dec(task::current_id() as u32);
+        puts("\n");
+        task::kill_current(ctx)
+    } else {
+        panic::panic_context("unhandled abort", &*ctx)
+    }
+}
+
+fn report(what: &str, fsr: u32, address: u32) {
+    puts("\n*** ");
+    puts(what);
+    puts(": ");
+    puts(describe(fsr));
+    puts(" fault at ");
+    put_hex(address);
+    puts(", domain ");
+    put_dec((fsr >> 4) & 0xF);
+    puts("\n");
+}
@@ -1,8 +1,17 @@
 use core::mem::{volatile_store, transmute};
 use core::ptr::offset;
+use core::option::{Option, Some, None};
+
+use platform::drivers::board;
+use kernel::panic;
+use kernel::task;
 
 static VT: *u32 = 0 as *u32;
 
+/// Room in the handler table: at least `Board::nr_irqs` of every
+/// supported board
+static MAX_IRQS: uint = 72;
+
 #[repr(u8)]
 pub enum Int {
     RESET = 0,
@@ -14,6 +23,97 @@ pub enum Int {
     FIQ
 }
 
+/// Registers saved by the exception entry stubs in `boot/loader.s`, lowest
+/// address first. `pc` is the address execution resumes at.
+#[packed]
+pub struct Context {
+    usr_sp: u32,
+    usr_lr: u32,
+    spsr: u32,
+    r: [u32, ..13],
+    lr: u32,
+    pc: u32,
+}
+
+pub type IrqHandler = unsafe fn(u8);
+
+static mut irq_handlers: [Option<IrqHandler>, ..MAX_IRQS] = [None, ..MAX_IRQS];
+
+/// Route interrupt `line` to `handler` and unmask it at the interrupt
+/// controller. Replaces any handler already on the line.
+pub fn register_irq(line: u8, handler: IrqHandler) -> bool {
+    if !valid(line) {
+        return false;
+    }
+    unsafe {
+        irq_handlers[line as uint] = Some(handler);
+        board().irq_enable(line);
+    }
+    true
+}
+
+/// Mask interrupt `line` and forget its handler.
+pub fn unregister_irq(line: u8) {
+    if !valid(line) {
+        return;
+    }
+    unsafe {
+        board().irq_disable(line);
+        irq_handlers[line as uint] = None;
+    }
+}
+
+/// Does the board's controller have `line`, and the table room for it?
+fn valid(line: u8) -> bool {
+    (line as uint) < board().nr_irqs() && (line as uint) < MAX_IRQS
+}
+
+/// Called from `irq_entry` with the interrupted context. Services every
+/// pending line, highest priority first, and returns the context to resume.
+#[no_mangle]
+pub unsafe extern "C" fn irq_dispatch(ctx: *mut Context) -> *mut Context {
+    loop {
+        match board().irq_next_pending() {
+            Some(line) => match irq_handlers[line as uint] {
+                Some(handler) => handler(line),
+                // Nobody listens on this line; mask it instead of spinning
+                None => board().irq_disable(line)
+            },
+            None => break
+        }
+    }
+    board().irq_ack();
+    task::preempt(ctx)
+}
+
+/// Mask IRQs, returning the previous state for `restore`.
+pub fn disable() -> u32 {
+    let cpsr: u32;
+    unsafe {
+        asm!("mrs $0, cpsr
+              orr r1, $0, #0x80
+              msr cpsr_c, r1"
+            : "=r"(cpsr) :: "r1");
+    }
+    cpsr
+}
+
+/// Are IRQs unmasked on this CPU?
+pub fn enabled() -> bool {
+    let cpsr: u32;
+    unsafe {
+        asm!("mrs $0, cpsr" : "=r"(cpsr));
+    }
+    cpsr & 0x80 == 0
+}
+
+/// Restore the IRQ mask saved by `disable`.
+pub fn restore(cpsr: u32) {
+    unsafe {
+        asm!("msr cpsr_c, $0" :: "r"(cpsr));
+    }
+}
+
 fn set_word(vector: u8, instruction: u32) {
     unsafe {
         volatile_store(offset(VT, vector as int) as *mut u32, instruction);
@@ -55,6 +155,10 @@ impl Table {
         self.enable(RESET, unsafe { transmute(start) });
         // breakpoints use an UND opcode to trigger UNDEF. [7]
         self.enable(UNDEF, debug);
+        self.enable(SWI, unsafe { transmute(swi_entry) });
+        self.enable(PREFETCH_ABORT, unsafe { transmute(prefetch_abort_entry) });
+        self.enable(DATA_ABORT, unsafe { transmute(data_abort_entry) });
+        self.enable(IRQ, unsafe { transmute(irq_entry) });
 
         unsafe {
             // Enable IRQs [5]
@@ -64,17 +168,24 @@ impl Table {
               orr r1, r1, #0x12
               msr cpsr, r1
               mov sp, 0x19000   // set IRQ stack
+              bic r1, r0, #0x1F // go in Abort mode
+              orr r1, r1, #0x17
+              msr cpsr, r1
+              mov sp, 0x18C00   // set Abort stack
               bic r0, r0, #0x80 // Enable IRQs
               msr cpsr, r0      // go back in Supervisor mode
               mov sp, r2"
             ::: "r0", "r1", "r2", "cpsr");
-            // Call arm976ej_s::serial::UART0.open(baud) to open
         }
     }
 }
 
 extern {
     fn start();
+    fn irq_entry();
+    fn swi_entry();
+    fn prefetch_abort_entry();
+    fn data_abort_entry();
 }
 
 #[no_mangle]
@@ -82,16 +193,12 @@ pub unsafe fn debug() {
     asm!("movs pc, lr")
 }
 
-/*
 #[lang="fail_"]
-#[fixed_stack_segment]
 pub fn fail(expr: *u8, file: *u8, line: uint) -> ! {
-    unsafe { zero::abort(); }
+    unsafe { panic::panic(panic::c_str(expr), panic::c_str(file), line) }
 }
 
 #[lang="fail_bounds_check"]
-#[fixed_stack_segment]
-pub fn fail_bounds_check(file: *u8, line: uint, index: uint, len: uint) {
-    unsafe { zero::abort(); }
+pub fn fail_bounds_check(file: *u8, line: uint, index: uint, len: uint) -> ! {
+    unsafe { panic::panic_bounds(index, len, panic::c_str(file), line) }
 }
-*/
@@ -1,18 +1,40 @@
+use core::mem::size_of;
+use core::option::{Option, Some, None};
+use core::ptr::copy_memory;
+
+use kernel;
 use kernel::memory::physical;
+use platform::cpu::interrupt;
+use platform::drivers::board;
 
-#[allow(dead_code)]
-static CACHE:  u32 = 1 << 3;
-#[allow(dead_code)]
-static BUFFER: u32 = 1 << 2;
-#[allow(dead_code)]
+pub static CACHE:  u32 = 1 << 3;
+pub static BUFFER: u32 = 1 << 2;
 pub static SECTION: u32 = 0b10010;
+// Level 1 entry pointing at a coarse page table (bit 4 should be one)
+static COARSE:     u32 = 0b10001;
+// Level 2 entry for a 4 KiB page
+static SMALL_PAGE: u32 = 0b10;
+static TYPE_MASK:  u32 = 0b11;
+
+// Access permissions, in their section descriptor position
 pub static RW:      u32 = 1 << 10;
 pub static USER:    u32 = 1 << 11;
+static AP_SHIFT:    u32 = 10;
+
+pub static PAGE_SIZE:    u32 = 1 << 12;
+pub static SECTION_SIZE: u32 = 1 << 20;
+
+/// Per-address-space region. Everything outside it is the kernel's,
+/// shared by every page directory and privileged-only.
+pub static USER_BASE:  u32 = 0x40000000;
+pub static USER_LIMIT: u32 = 0x80000000;
+
+// Domain 0 is a client: accesses are checked against the AP bits
+static DOMAIN0_CLIENT: u32 = 0b01;
 
 #[packed]
 struct Descriptor(u32);
 
-#[allow(dead_code)]
 #[packed]
 struct PageTableCoarse {
     pages: [Descriptor, ..256]
@@ -23,15 +45,53 @@ pub struct PageDirectory {
     tables: [Descriptor, ..4096]
 }
 
+pub static mut kernel_dir: *mut PageDirectory = 0 as *mut PageDirectory;
+
+/// Every directory made by `new`, so kernel mappings added later reach them
+struct Linked {
+    dir: *mut PageDirectory,
+    next: *mut Linked
+}
+
+static mut directories: *mut Linked = 0 as *mut Linked;
+
+/// Identity-map RAM and the chip's peripherals, then turn the MMU on.
 pub unsafe fn init() {
     let dir = physical::zero_alloc_frames(4) as *mut PageDirectory;
 
-    (*dir).tables[0] = Descriptor::section(0, RW);
+    // RAM from address 0: vectors, kernel, heap, frames and framebuffer
+    (*dir).identity_map(0, physical::ram_end() as u32, RW);
+    let (io_base, io_size) = board().io_window();
+    (*dir).identity_map(io_base, io_base + io_size, RW);
+
+    kernel_dir = dir;
+    asm!("mcr p15, 0, $0, c3, c0, 0" :: "r"(DOMAIN0_CLIENT));
     (*dir).enable();
+    asm!("mrc p15, 0, r0, c1, c0, 0
+          orr r0, r0, #1            // M: enable MMU
+          mcr p15, 0, r0, c1, c0, 0"
+        ::: "r0");
+}
+
+/// Map the page at `virt` to `phys` in the kernel page directory.
+pub unsafe fn map(virt: u32, phys: u32, flags: u32) {
+    (*kernel_dir).map(virt, phys, flags);
 }
 
-pub unsafe fn map(page_ptr: *mut u8, flags: u32) {
-    // TODO
+pub unsafe fn unmap(virt: u32) {
+    (*kernel_dir).unmap(virt);
+}
+
+pub unsafe fn translate(virt: u32) -> Option<u32> {
+    (*kernel_dir).translate(virt)
+}
+
+#[inline]
+unsafe fn invalidate_tlb_entry(virt: u32) {
+    asm!("mov ip, 0
+          mcr p15, 0, ip, c7, c10, 4    // drain WB
+          mcr p15, 0, $0, c8, c7, 1     // invalidate TLB entry for MVA"
+        :: "r"(virt) : "ip")
 }
 
 impl Descriptor {
@@ -40,9 +100,115 @@ impl Descriptor {
         //                /permissions
         Descriptor(base | flags | SECTION)
     }
+
+    fn coarse(table: *mut PageTableCoarse) -> Descriptor {
+        Descriptor(table as u32 | COARSE)
+    }
+
+    fn page(base: u32, flags: u32) -> Descriptor {
+        // one AP field per 1 KiB subpage, all set alike
+        let ap = (flags >> AP_SHIFT) & 3;
+        Descriptor((base & !(PAGE_SIZE - 1))
+                   | ap << 4 | ap << 6 | ap << 8 | ap << 10
+                   | flags & (CACHE | BUFFER)
+                   | SMALL_PAGE)
+    }
+}
+
+fn is_user(index: uint) -> bool {
+    index >= (USER_BASE >> 20) as uint && index < (USER_LIMIT >> 20) as uint
 }
 
 impl PageDirectory {
+    /// A fresh directory with the kernel mapped and an empty user region.
+    pub unsafe fn new() -> *mut PageDirectory {
+        let dir = physical::zero_alloc_frames(4) as *mut PageDirectory;
+        let link = kernel::zero_alloc(size_of::<Linked>()) as *mut Linked;
+        (*link).dir = dir;
+
+        // No kernel entry may change between the copy and joining the list
+        let irq = interrupt::disable();
+        let mut i = 0;
+        while i < 4096 {
+            if !is_user(i) {
+                (*dir).tables[i] = (*kernel_dir).tables[i];
+            }
+            i += 1;
+        }
+        (*link).next = directories;
+        directories = link;
+        interrupt::restore(irq);
+        dir
+    }
+
+    /// Copy of this directory with a private copy of every user page.
+    pub unsafe fn clone(&self) -> *mut PageDirectory {
+        let dir = PageDirectory::new();
+        let mut i = 0;
+        while i < 4096 {
+            let Descriptor(entry) = self.tables[i];
+            if is_user(i) && entry & TYPE_MASK == 0b01 {
+                let table = (entry & !0x3FF) as *mut PageTableCoarse;
+                let mut j = 0;
+                while j < 256 {
+                    let Descriptor(page) = (*table).pages[j];
+                    if page & TYPE_MASK == SMALL_PAGE {
+                        let frame = physical::alloc_frames(1);
+                        copy_memory(frame, (page & !(PAGE_SIZE - 1)) as *u8, PAGE_SIZE as uint);
+                        let flags = ((page >> 4) & 3) << AP_SHIFT | page & (CACHE | BUFFER);
+                        (*dir).map((i << 20 | j << 12) as u32, frame as u32, flags);
+                    }
+                    j += 1;
+                }
+            }
+            i += 1;
+        }
+        dir
+    }
+
+    /// Free a directory made by `new` or `clone`, along with its user page
+    /// tables and the frames they map. User pages are owned by their space.
+    pub unsafe fn destroy(dir: *mut PageDirectory) {
+        let irq = interrupt::disable();
+        let mut prev = 0 as *mut Linked;
+        let mut link = directories;
+        while link as uint != 0 {
+            if (*link).dir == dir {
+                if prev as uint == 0 {
+                    directories = (*link).next;
+                } else {
+                    (*prev).next = (*link).next;
+                }
+                kernel::free(link as *mut u8);
+                break;
+            }
+            prev = link;
+            link = (*link).next;
+        }
+        interrupt::restore(irq);
+
+        let mut i = 0;
+        while i < 4096 {
+            let Descriptor(entry) = (*dir).tables[i];
+            if is_user(i) && entry & TYPE_MASK == 0b01 {
+                let table = (entry & !0x3FF) as *mut PageTableCoarse;
+                let mut j = 0;
+                while j < 256 {
+                    let Descriptor(page) = (*table).pages[j];
+                    if page & TYPE_MASK == SMALL_PAGE {
+                        physical::free_frames((page & !(PAGE_SIZE - 1)) as *mut u8);
+                    }
+                    j += 1;
+                }
+                physical::free_frames(table as *mut u8);
+            }
+            i += 1;
+        }
+        physical::free_frames(dir as *mut u8);
+    }
+
+    /// Switch translation to this directory. ARMv5 has no address space
+    /// identifiers, so this flushes the whole TLB.
     pub unsafe fn enable(&self) {
         asm!("mov ip, 0
               mcr p15, 0, ip, c7, c5, 0     // invalidate I cache
@@ -51,4 +217,140 @@ impl PageDirectory {
               mcr p15, 0, ip, c8, c7, 0     // invalidate I & D TLBs"
             :: "{r0}"(self) : "ip")
     }
+
+    /// Map [start, end) onto itself, in sections where alignment allows.
+    pub unsafe fn identity_map(&mut self, start: u32, end: u32, flags: u32) {
+        let mut addr = start & !(PAGE_SIZE - 1);
+        while addr < end {
+            if addr & (SECTION_SIZE - 1) == 0 && end - addr >= SECTION_SIZE {
+                self.tables[addr >> 20] = Descriptor::section(addr, flags);
+                self.share(addr >> 20);
+                invalidate_tlb_entry(addr);
+                addr += SECTION_SIZE;
+            } else {
+                self.map(addr, addr, flags);
+                addr += PAGE_SIZE;
+            }
+        }
+    }
+
+    /// Map the 4 KiB page at `virt` to `phys`.
+    pub unsafe fn map(&mut self, virt: u32, phys: u32, flags: u32) {
+        if !self.try_map(virt, phys, flags) {
+            kpanic!("out of physical frames");
+        }
+    }
+
+    /// `map`, failing instead of panicking if there is no frame left for
+    /// a page table.
+    pub unsafe fn try_map(&mut self, virt: u32, phys: u32, flags: u32) -> bool {
+        match self.coarse_table(virt) {
+            Some(table) => {
+                (*table).pages[(virt >> 12) & 0xFF] = Descriptor::page(phys, flags);
+                invalidate_tlb_entry(virt);
+                true
+            }
+            None => false
+        }
+    }
+
+    pub unsafe fn unmap(&mut self, virt: u32) {
+        let Descriptor(entry) = self.tables[virt >> 20];
+        match entry & TYPE_MASK {
+            0b00 => return,
+            _ => ()
+        }
+        match self.coarse_table(virt) {
+            Some(table) => {
+                (*table).pages[(virt >> 12) & 0xFF] = Descriptor(0);
+                invalidate_tlb_entry(virt);
+            }
+            None => ()
+        }
+    }
+
+    /// Physical address `virt` maps to, if any.
+    pub unsafe fn translate(&self, virt: u32) -> Option<u32> {
+        let Descriptor(entry) = self.tables[virt >> 20];
+        match entry & TYPE_MASK {
+            0b10 => Some((entry & !(SECTION_SIZE - 1)) | (virt & (SECTION_SIZE - 1))),
+            0b01 => {
+                let table = (entry & !0x3FF) as *mut PageTableCoarse;
+                let Descriptor(page) = (*table).pages[(virt >> 12) & 0xFF];
+                match page & TYPE_MASK {
+                    0b10 => Some((page & !(PAGE_SIZE - 1)) | (virt & (PAGE_SIZE - 1))),
+                    _ => None
+                }
+            }
+            _ => None
+        }
+    }
+
+    /// Access flags, `RW` and `USER`, of the mapping for `virt` if any.
+    pub unsafe fn access(&self, virt: u32) -> Option<u32> {
+        let Descriptor(entry) = self.tables[virt >> 20];
+        match entry & TYPE_MASK {
+            0b10 => Some(entry & (RW | USER)),
+            0b01 => {
+                let table = (entry & !0x3FF) as *mut PageTableCoarse;
+                let Descriptor(page) = (*table).pages[(virt >> 12) & 0xFF];
+                match page & TYPE_MASK {
+                    0b10 => Some(((page >> 4) & 3) << AP_SHIFT),
+                    _ => None
+                }
+            }
+            _ => None
+        }
+    }
+
+    /// The coarse table covering `virt`, allocating one if needed. A section
+    /// mapping in the way is split into pages with the same attributes.
+    /// None if no frame is left for a new table.
+    unsafe fn coarse_table(&mut self, virt: u32) -> Option<*mut PageTableCoarse> {
+        let index = virt >> 20;
+        let Descriptor(entry) = self.tables[index];
+        match entry & TYPE_MASK {
+            0b01 => return Some((entry & !0x3FF) as *mut PageTableCoarse),
+            _ => ()
+        }
+
+        // Coarse tables are 1 KiB; take a whole frame to keep them aligned
+        // and simple to free.
+        let table = match physical::try_zero_alloc_frames(1) {
+            Some(frame) => frame as *mut PageTableCoarse,
+            None => return None
+        };
+        match entry & TYPE_MASK {
+            0b10 => {
+                let base = entry & !(SECTION_SIZE - 1);
+                let flags = entry & (RW | USER | CACHE | BUFFER);
+                let mut i = 0;
+                while i < 256 {
+                    (*table).pages[i] = Descriptor::page(base + (i as u32 << 12), flags);
+                    i += 1;
+                }
+            }
+            _ => ()
+        }
+        self.tables[index] = Descriptor::coarse(table);
+        self.share(index);
+        invalidate_tlb_entry(virt);
+        Some(table)
+    }
+
+    /// If this is the kernel directory, copy its level 1 entry `index` into
+    /// every other directory. Entries below that level are shared already.
+    unsafe fn share(&self, index: u32) {
+        let me = self as *PageDirectory as *mut PageDirectory;
+        if me != kernel_dir || is_user(index as uint) {
+            return;
+        }
+        let irq = interrupt::disable();
+        let mut link = directories;
+        while link as uint != 0 {
+            (*(*link).dir).tables[index] = self.tables[index];
+            link = (*link).next;
+        }
+        interrupt::restore(irq);
+    }
 }
@@ -1,9 +1,10 @@
 pub mod interrupt;
+pub mod fault;
 pub mod mmu;
 
 pub fn init() {
     unsafe {
-        // mmu::init();
+        mmu::init();
     }
 }
 
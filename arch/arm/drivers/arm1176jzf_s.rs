@@ -1,14 +1,252 @@
 /* drivers::arm1176jzf_s.rs */
 
-use kernel::screen::Resolution;
+use core::option::{Option, Some, None};
 
-pub fn init(_ : Resolution){
-    unsafe{
+use kernel;
+use kernel::board::Board;
+use kernel::device;
+use kernel::device::Driver;
+use kernel::screen::{ScreenCanvas, TerminalCanvas};
+use kernel::serial::Serial;
+use kernel::fs::devfs;
+
+/// BCM2835 peripherals, as seen by the ARM
+pub static IO_BASE : u32 = 0x20000000;
+pub static IO_SIZE : u32 = 0x01000000;
+
+/// UARTCLK as the firmware leaves it
+pub static UART_CLK : u32 = 3000000; // 3 MHz
+/// Core clock, which the mini UART divides down
+pub static CORE_CLK : u32 = 250000000; // 250 MHz
+
+static GPIO_DEV : Driver = Driver {
+    name : "gpio", compatible : "brcm,bcm2835-gpio", base : 0x20200000, id : 0,
+    caps : 0, probe : device::always, init : gpio_init, shutdown : None
+};
+static UART0_DEV : Driver = Driver {
+    name : "uart0", compatible : "arm,pl011", base : 0x20201000, id : 0,
+    caps : device::SERIAL, probe : uart_probe, init : uart_init, shutdown : Some(uart_shutdown)
+};
+static UART1_DEV : Driver = Driver {
+    name : "uart1", compatible : "brcm,bcm2835-aux-uart", base : 0x20215040, id : 1,
+    caps : device::SERIAL, probe : uart_probe, init : uart_init, shutdown : Some(uart_shutdown)
+};
+static FB0 : Driver = Driver {
+    name : "fb0", compatible : "brcm,bcm2835-mbox-fb", base : mailman::BASE, id : 0,
+    caps : device::CANVAS, probe : device::always, init : fb_init, shutdown : Some(fb_shutdown)
+};
+
+/// Handle of the log sink drawing on the screen
+static mut screen_sink : Option<uint> = None;
+
+pub fn init(){
+    device::register(&GPIO_DEV);
+    device::register(&UART0_DEV);
+    device::register(&UART1_DEV);
+    device::register(&FB0);
+}
+
+/// Turn the ACT LED (GPIO 16, active low) on to show we got this far.
+fn gpio_init(_ : &Driver) -> bool
+{
+    unsafe {
         let p = gpio::Pin::get(16).get();
         p.setMode(gpio::OUTPUT);
         p.write(false);
     }
+    true
+}
+
+/// Both ports use GPIO 14/15, so only the one `console=` names is
+/// brought up; the other is left alone.
+fn uart_probe(d : &Driver) -> bool
+{
+    d.id == kernel::serial::console_index()
+}
+
+/// Open the console port, taking the pins over. Until then the firmware
+/// leaves them on UART0, which carries early messages.
+fn uart_init(d : &Driver) -> bool
+{
+    match serial::get(d.id) {
+        Some(port) => {
+            if d.id == 0 {
+                serial::route(0);
+            }
+            port.open(kernel::serial::console_baud())
+                && devfs::register(d.name, devfs::SerialDev(port))
+        }
+        None => false
+    }
+}
+
+fn uart_shutdown(d : &Driver)
+{
+    devfs::unregister(d.name);
+    match serial::get(d.id) {
+        Some(port) => { port.close(); }
+        None => ()
+    }
+}
+
+/// Fails if the firmware won't give us a framebuffer, e.g. with no
+/// display attached.
+fn fb_init(_ : &Driver) -> bool
+{
+    unsafe {
+        let cv = &mut screen::Screen0;
+        let res = cv.setResolution(kernel::screen::requested());
+        if !cv.ready() {
+            return false;
+        }
+        cv.set_bg(kernel::screen::ARGBPixel(0x00, 0x22, 0x2C, 0x38));
+        cv.set_fg(kernel::screen::ARGBPixel(0x00, 0xFA, 0xFC, 0xFF));
+        cv.set_cursor_color(kernel::screen::ARGBPixel(0x00, 0xFA, 0xFC, 0xFF));
+        cv.fill_bg();
+
+        kinfo!("screen0: ", res.w, "x", res.h);
+        screen_sink = kernel::log::add_sink(log_to_screen);
+        devfs::register("fb0", devfs::CanvasDev(cv as &'static mut TerminalCanvas))
+    }
+}
+
+fn fb_shutdown(d : &Driver)
+{
+    devfs::unregister(d.name);
+    unsafe {
+        match screen_sink {
+            Some(h) => kernel::log::remove_sink(h),
+            None => ()
+        }
+        screen_sink = None;
+    }
+}
 
+/// Log sink drawing records on the framebuffer console
+fn log_to_screen(c: u8)
+{
+    unsafe { screen::Screen0.putChar(c); }
+}
+
+/// A Raspberry Pi: BCM2835 with an ARM1176JZF-S
+pub struct RaspberryPi;
+
+pub static BOARD : RaspberryPi = RaspberryPi;
+
+impl Board for RaspberryPi
+{
+    fn name(&self) -> &'static str { "raspberrypi" }
+    unsafe fn init(&self) { init() }
+
+    fn io_window(&self) -> (u32, u32) { (IO_BASE, IO_SIZE) }
+    // The ARM's share depends on the model and the GPU split; the firmware
+    // always passes it in ATAGs or a device tree
+    fn default_ram(&self) -> Option<(uint, uint)> { None }
+
+    fn serial_count(&self) -> uint { serial::COUNT }
+    fn serial(&self, i : uint) -> Option<&'static mut Serial> { serial::get(i) }
+    fn serial_name(&self, i : uint) -> Option<&'static str> { serial::name(i) }
+    fn putc_raw(&self, c : u8) { serial::putc_raw(c) }
+
+    fn display(&self) -> Option<&'static mut TerminalCanvas>
+    {
+        unsafe {
+            if screen::Screen0.ready() {
+                Some(&mut screen::Screen0 as &'static mut TerminalCanvas)
+            } else {
+                None
+            }
+        }
+    }
+
+    fn nr_irqs(&self) -> uint { irq::NR_IRQS }
+    unsafe fn irq_enable(&self, line : u8) { irq::enable(line) }
+    unsafe fn irq_disable(&self, line : u8) { irq::disable(line) }
+    unsafe fn irq_next_pending(&self) -> Option<u8> { irq::next_pending() }
+    unsafe fn irq_ack(&self) { irq::ack() }
+
+    fn timer_start(&self, hz : u32, handler : fn()) -> bool { timer::start(hz, handler) }
+    fn timer_stop(&self) { timer::stop() }
+    fn timer_elapsed_us(&self) -> u32 { timer::elapsed_us() }
+}
+
+/// driver::arm1176jzf_s::irq
+/// BCM2835 interrupt controller. Lines 0-63 are the GPU peripheral
+/// interrupts; 64-71 are the ARM-side sources in the basic registers.
+// See BCM2835 ARM Peripherals, chapter 7
+pub mod irq
+{
+    use core::option::{Option, Some, None};
+    use core::i32::cttz32;
+    use platform::io;
+
+    pub static NR_IRQS : uint = 72;
+
+    static BASE          : u32 = 0x2000B200;
+    static PENDING_BASIC : u32 = BASE + 0x00;
+    static PENDING_1     : u32 = BASE + 0x04;
+    static PENDING_2     : u32 = BASE + 0x08;
+    static ENABLE_1      : u32 = BASE + 0x10;
+    static ENABLE_BASIC  : u32 = BASE + 0x18;
+    static DISABLE_1     : u32 = BASE + 0x1C;
+    static DISABLE_BASIC : u32 = BASE + 0x24;
+
+    /// Line of basic bit 0, the ARM timer
+    static BASIC_FIRST   : u8 = 64;
+    /// Basic pending bits 0-7 are ARM sources; the rest mirror GPU lines
+    static BASIC_MASK    : u32 = 0xFF;
+
+    /// Enable or disable register for `line`, and its bit there. Lines
+    /// 0-31 and 32-63 have a register each; the basic one follows them.
+    fn reg(first : u32, basic : u32, line : u8) -> (u32, u32)
+    {
+        if line >= BASIC_FIRST {
+            (basic, 1 << (line - BASIC_FIRST))
+        } else {
+            (first + 4 * (line / 32) as u32, 1 << (line % 32))
+        }
+    }
+
+    pub unsafe fn enable(line : u8)
+    {
+        if (line as uint) < NR_IRQS {
+            let (r, bit) = reg(ENABLE_1, ENABLE_BASIC, line);
+            io::wh(r, bit);
+        }
+    }
+
+    pub unsafe fn disable(line : u8)
+    {
+        if (line as uint) < NR_IRQS {
+            let (r, bit) = reg(DISABLE_1, DISABLE_BASIC, line);
+            io::wh(r, bit);
+        }
+    }
+
+    /// Lowest numbered ARM source with an enabled request, then the
+    /// lowest GPU line
+    pub unsafe fn next_pending() -> Option<u8>
+    {
+        let basic = io::read(PENDING_BASIC) & BASIC_MASK;
+        if basic != 0 {
+            return Some(BASIC_FIRST + cttz32(basic as i32) as u8);
+        }
+        let p1 = io::read(PENDING_1);
+        if p1 != 0 {
+            return Some(cttz32(p1 as i32) as u8);
+        }
+        let p2 = io::read(PENDING_2);
+        if p2 != 0 {
+            return Some(32 + cttz32(p2 as i32) as u8);
+        }
+        None
+    }
+
+    /// Nothing to signal: requests are level-sensitive and go away once
+    /// the handlers clear them at the source.
+    pub unsafe fn ack()
+    {
+    }
 }
 
 /// driver::arm1176jzf_s::mailman 
@@ -40,10 +278,12 @@ mod mailman{
     static PROP_TAGS_SEND	: MailmanBoxNum	= 8;
     static PROP_TAGS_RECV	: MailmanBoxNum	= 9;
 
+    static PROP_RESPONSE_OK	: u32	= 0x80000000;
+
 
     /// Reads from the appropriate mailbox. Discards other mailboxes' messages and will hang until message received.
     /// Returns (message, true) on success, (0, false) on user error.
-    unsafe fn postman_read(mailbox : MailmanBoxNum) -> (u32, bool){
+    pub unsafe fn postman_read(mailbox : MailmanBoxNum) -> (u32, bool){
         let mut message : u32 = 0;
         loop{
             while(io::read(BASE + STATUS_OFFSET) & STATUS_MSG_PENDING_MASK != 0) {};
@@ -56,7 +296,7 @@ mod mailman{
 
     /// Reads from the appropriate mailbox. Will hang until message received.
     /// Returns true on success, false on user error.
-    unsafe fn postman_write(mailbox : MailmanBoxNum, message : u32) -> bool{
+    pub unsafe fn postman_write(mailbox : MailmanBoxNum, message : u32) -> bool{
         if(message & 0xF != 0){
             return false; //Error: message bigger than mailbox number
         }
@@ -67,43 +307,165 @@ mod mailman{
 
         return true;
     }
+
+    /// Hand a property tag buffer to the firmware on channel 8 and wait
+    /// for the reply. `buf` must be 16-byte aligned; its first word is the
+    /// buffer size in bytes and the second is overwritten with the result.
+    /// Returns true if the firmware processed every tag.
+    pub unsafe fn property(buf : *mut u32) -> bool{
+        // The VideoCore reads RAM through its L2 alias, past our D-cache
+        asm!("mcr p15, 0, $0, c7, c10, 0
+              mcr p15, 0, $0, c7, c10, 4" :: "r"(0u32));
+        let addr = (buf as u32) | 0x40000000;
+        if !postman_write(PROP_TAGS_SEND, addr){
+            return false;
+        }
+        let (reply, _) = postman_read(PROP_TAGS_SEND);
+        reply == addr && *((buf as u32 + 4) as *u32) == PROP_RESPONSE_OK
+    }
 }
 
 /// driver::arm1176jzf_s::screen
-/// Driver for communicating with GPU, drawing to screen
+/// Framebuffer allocated by the VideoCore through the mailbox property
+/// interface, drawn to as 32-bit pixels
+// See https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface
 pub mod screen{
+    use core::ptr::copy_memory;
     use kernel::screen::*;
+    use kernel::screen::font;
+    use kernel::memory::physical;
+    use platform::cpu::mmu;
     use super::mailman;
 
-    struct screen_buffer_info{
+    static TAG_ALLOCATE_BUFFER  : u32 = 0x00040001;
+    static TAG_GET_PITCH        : u32 = 0x00040008;
+    static TAG_SET_PHYS_SIZE    : u32 = 0x00048003;
+    static TAG_SET_VIRT_SIZE    : u32 = 0x00048004;
+    static TAG_SET_DEPTH        : u32 = 0x00048005;
+
+    /// Words in the request built by `sync`
+    static MSG_WORDS : uint = 26;
+
+    /// Property buffer shared with the firmware; a whole frame, so it is
+    /// suitably aligned
+    static mut msg : *mut u32 = 0 as *mut u32;
+
+    pub struct screen_buffer_info{
         /// requested / provided width of screen
         width       : u32,
         /// requested / provided width of screen
         height      : u32,
-        v_width     : u32, 
+        v_width     : u32,
         v_height    : u32,
         /// pitch between screen rows; provdided by graphics driver
         pitch       : u32,
         /// color depth. 16 for hicolor, 24 for truecolor, 32 for RGBA32
-        depth       : u32, 
+        depth       : u32,
         /// x offset of display
-        x           : u32, 
+        x           : u32,
         /// y offset of screen
-        y           : u32, 
+        y           : u32,
         /// pointer to graphics area of memory
-        pointer     : *mut u32, 
+        pointer     : *mut u32,
         /// Size of graphics buffer (bytes)
-        size        : u32  
+        size        : u32,
+
+        CURSOR          : cursor,
+        CURSOR_BUFFER   : [u32, ..8*16],
+        SAVE_X          : u32,
+        SAVE_Y          : u32,
+    }
+
+    pub static mut Screen0 : screen_buffer_info = screen_buffer_info{
+            width       : 0,
+            height      : 0,
+            v_width     : 0,
+            v_height    : 0,
+            pitch       : 0,
+            depth       : 32,
+            x           : 0,
+            y           : 0,
+            pointer     : 0 as *mut u32,
+            size        : 0,
+            CURSOR : cursor{
+                x      : 0,
+                y      : 0,
+                height : 16,
+                width  : 8,
+                cursor_color  : ARGBPixel(0, 0, 0, 0xFF),
+                fg_color      : ARGBPixel(0, 0, 0, 0),
+                bg_color      : ARGBPixel(0, 0xFF, 0xFF, 0xFF),
+            },
+            CURSOR_BUFFER   : [0, ..8*16],
+            SAVE_X          : 0,
+            SAVE_Y          : 0,
+    };
+
+    /// Word `i` of the property buffer
+    unsafe fn word(i : uint) -> *mut u32
+    {
+        (msg as uint + i*4) as *mut u32
     }
 
     impl ScreenCanvas for screen_buffer_info{
+        /// Ask the firmware for a framebuffer of the current size and
+        /// depth, then record what it actually gave us. Fails unless that
+        /// is 32 bits per pixel.
         fn sync(&mut self) -> bool{
+            unsafe {
+                if msg as uint == 0 {
+                    msg = physical::zero_alloc_frames(1) as *mut u32;
+                }
+                let request : [u32, ..MSG_WORDS] = [
+                    (MSG_WORDS*4) as u32, 0,
+                    TAG_SET_PHYS_SIZE, 8, 0, self.width, self.height,
+                    TAG_SET_VIRT_SIZE, 8, 0, self.v_width, self.v_height,
+                    TAG_SET_DEPTH, 4, 0, self.depth,
+                    TAG_ALLOCATE_BUFFER, 8, 0, 16, 0,
+                    TAG_GET_PITCH, 4, 0, 0,
+                    0
+                ];
+                let mut i = 0;
+                while i < MSG_WORDS {
+                    *word(i) = request[i];
+                    i += 1;
+                }
+
+                if !mailman::property(msg) {
+                    return false;
+                }
+                // The buffer comes back as a bus address
+                let base = *word(19) & 0x3FFFFFFF;
+                if base == 0 {
+                    return false;
+                }
+                // Everything below draws 32-bit pixels; the firmware may
+                // have laid the new buffer out otherwise, so drop it
+                if *word(15) != 32 {
+                    self.pointer = 0 as *mut u32;
+                    return false;
+                }
+                self.width      = *word(5);
+                self.height     = *word(6);
+                self.v_width    = *word(10);
+                self.v_height   = *word(11);
+                self.depth      = *word(15);
+                self.size       = *word(20);
+                self.pitch      = *word(24);
+                self.pointer    = base as *mut u32;
+
+                // It lives in the GPU's share of RAM, above what the MMU
+                // mapped at boot
+                if mmu::kernel_dir as uint != 0 {
+                    (*mmu::kernel_dir).identity_map(base, base + self.size, mmu::RW);
+                }
+            }
             true
         }
 
-        fn getResolution(&self) -> Resolution  
-        { 
-            Resolution{w: self.width as uint, h :self.height as uint} 
+        fn getResolution(&self) -> Resolution
+        {
+            Resolution{w: self.width as uint, h :self.height as uint}
         }
 
         fn setResolution(&mut self, res : Resolution) -> Resolution
@@ -113,7 +475,7 @@ pub mod screen{
             self.height     = res.h as u32;
             self.v_width    = res.w as u32;
             self.v_height   = res.h as u32;
-            
+
             if(!self.sync()){
                 self.width      = prev.w as u32;
                 self.height     = prev.h as u32;
@@ -141,16 +503,666 @@ pub mod screen{
             self.getColorDepth()
         }
 
-        fn drawPixel(&mut self, color: &Pixel, coords : &(uint, uint)) -> bool{ false }
+        fn drawPixel(&mut self, color: &Pixel, coords : &(uint, uint)) -> bool{
+            let (x, y) = *coords;
+            if !self.ready() || x >= self.width as uint || y >= self.height as uint {
+                return false;
+            }
+            let row = self.pointer as u32 + (y as u32)*self.pitch;
+            unsafe {
+                match (self.depth, *color) {
+                    (32, ARGBPixel(_, _, _, _)) => *((row + 4*x as u32) as *mut u32) = color.word(),
+                    (16, HighColorPixel(_, _, _)) => *((row + 2*x as u32) as *mut u16) = color.word() as u16,
+                    _ => return false
+                }
+            }
+            true
+        }
+
+        fn ready(&mut self) -> bool
+        {
+            self.pointer as uint != 0
+        }
+    }
 
-        fn ready(&mut self) -> bool { true }
+    impl TerminalCanvas for screen_buffer_info
+    {
+        fn getCursor(&self) -> cursor
+        {
+            self.CURSOR
+        }
+        fn setCursor(&mut self, c : &cursor) -> cursor
+        {
+            self.CURSOR = *c;
+            self.CURSOR
+        }
+        unsafe fn scrollup(&mut self)
+        {
+            if !self.ready()
+            {
+                return;
+            }
+            let h = self.CURSOR.height;
+            let kept = self.height - h;
+            copy_memory(self.pointer as *mut u8,
+                        (self.pointer as u32 + h*self.pitch) as *u8,
+                        (kept*self.pitch) as uint);
+            let bg = self.CURSOR.bg_color.word();
+            let mut y = kept;
+            while y < self.height
+            {
+                let mut x = 0;
+                while x < self.width
+                {
+                    *self.at(x, y) = bg;
+                    x += 1;
+                }
+                y += 1;
+            }
+            self.CURSOR.x = 0;
+            self.CURSOR.y -= h;
+        }
+        unsafe fn drawCharacter(&mut self, c: char) -> bool
+        {
+            if !self.ready()
+            {
+                return false;
+            }
+            if self.CURSOR.y + self.CURSOR.height > self.height
+            {
+                self.scrollup();
+            }
+            let map = font::bitmaps[(c as u8) - 0x20];
+            let fg = self.CURSOR.fg_color.word();
+            let bg = self.CURSOR.bg_color.word();
+
+            let mut j = 0;
+            while j < self.CURSOR.height
+            {
+                let mut i = 0;
+                while i < self.CURSOR.width
+                {
+                    // One nibble per pixel, lowest on the right
+                    let x = self.CURSOR.x + self.CURSOR.width - 1 - i;
+                    *self.at(x, self.CURSOR.y + j) = if ((map[j] >> 4*i) & 1) == 1 { fg } else { bg };
+                    i += 1;
+                }
+                j += 1;
+            }
+            true
+        }
+
+        unsafe fn backup(&mut self)
+        {
+            if !self.ready()
+            {
+                return;
+            }
+            let mut j = 0;
+            while j < self.CURSOR.height
+            {
+                let mut i = 0;
+                while i < self.CURSOR.width
+                {
+                    self.CURSOR_BUFFER[i + j*8] = *self.at(self.CURSOR.x + i, self.CURSOR.y + j);
+                    i += 1;
+                }
+                j += 1;
+            }
+            self.SAVE_X = self.CURSOR.x;
+            self.SAVE_Y = self.CURSOR.y;
+        }
+
+        unsafe fn restore(&mut self)
+        {
+            if !self.ready()
+            {
+                return;
+            }
+            let mut j = 0;
+            while j < self.CURSOR.height
+            {
+                let mut i = 0;
+                while i < self.CURSOR.width
+                {
+                    *self.at(self.SAVE_X + i, self.SAVE_Y + j) = self.CURSOR_BUFFER[i + j*8];
+                    i += 1;
+                }
+                j += 1;
+            }
+        }
+
+        unsafe fn drawCursor(&mut self)
+        {
+            if !self.ready()
+            {
+                return;
+            }
+            let color = self.CURSOR.cursor_color.word();
+            let mut j = 0;
+            while j < self.CURSOR.height
+            {
+                let mut i = 0;
+                while i < self.CURSOR.width
+                {
+                    *self.at(self.CURSOR.x + i, self.CURSOR.y + j) = color;
+                    i += 1;
+                }
+                j += 1;
+            }
+        }
     }
 
-        // Need screenbuf instance acquisition
+    impl screen_buffer_info
+    {
+        /// Address of the 32-bit pixel at (x, y)
+        unsafe fn at(&self, x : u32, y : u32) -> *mut u32
+        {
+            (self.pointer as u32 + y*self.pitch + x*4) as *mut u32
+        }
+
+        pub unsafe fn paint(&mut self, color: u32)
+        {
+            if !self.ready()
+            {
+                return;
+            }
+            let mut y = 0;
+            while y < self.height
+            {
+                let mut x = 0;
+                while x < self.width
+                {
+                    *self.at(x, y) = color;
+                    x += 1;
+                }
+                y += 1;
+            }
+        }
+
+        pub unsafe fn fill_bg(&mut self)
+        {
+            let word : u32 = self.CURSOR.bg_color.word();
+            self.paint(word);
+        }
+
+        pub unsafe fn set_fg(&mut self, color: Pixel)
+        {
+            self.CURSOR.fg_color = color;
+        }
+
+        pub unsafe fn set_bg(&mut self, color: Pixel)
+        {
+            self.CURSOR.bg_color = color;
+        }
+
+        pub unsafe fn set_cursor_color(&mut self, color: Pixel)
+        {
+            self.CURSOR.cursor_color = color;
+        }
+    }
 }
 
+/// driver::arm1176jzf_s::timer
+/// BCM2835 system timer: a free-running 64-bit microsecond counter with
+/// four compare registers. The GPU uses 0 and 2; compare 1 provides the
+/// system tick.
+// See BCM2835 ARM Peripherals, chapter 12
+pub mod timer
+{
+    use core::option::{Option, Some, None};
+    use platform::cpu::interrupt;
+    use platform::io;
+
+    static BASE     : u32 = 0x20003000;
+    static IRQ      : u8  = 1;
+    pub static TIMCLK : u32 = 1000000; // 1 MHz
+
+    static CS       : u32 = 0x00;
+    static CLO      : u32 = 0x04;
+    static CHI      : u32 = 0x08;
+    static C1       : u32 = 0x10;
+
+    static CS_M1    : u32 = 1 << 1;
+
+    static mut tick_handler : Option<fn()> = None;
+    /// Counts between ticks
+    static mut period : u32 = 0;
+    /// Counter value the last tick was due at
+    static mut last : u32 = 0;
+
+    /// Interrupt `hz` times a second, calling `handler` on every tick.
+    pub fn start(hz : u32, handler : fn()) -> bool
+    {
+        if hz == 0 || hz > TIMCLK {
+            return false;
+        }
+        unsafe {
+            tick_handler = Some(handler);
+            period = TIMCLK / hz;
+            last = io::read(BASE + CLO);
+            io::wh(BASE + C1, last + period);
+            io::wh(BASE + CS, CS_M1);
+            if !interrupt::register_irq(IRQ, timer_interrupt) {
+                tick_handler = None;
+                period = 0;
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn stop()
+    {
+        unsafe {
+            interrupt::unregister_irq(IRQ);
+            io::wh(BASE + CS, CS_M1);
+            tick_handler = None;
+            period = 0;
+        }
+    }
+
+    /// Microseconds since the last tick the handler has seen. While CS_M1
+    /// is pending that is one whole period: the handler counts one tick
+    /// however late it runs.
+    pub fn elapsed_us() -> u32
+    {
+        unsafe {
+            if period == 0 {
+                return 0;
+            }
+            // Counter first: a match after this read leaves it short of
+            // a period
+            let counts = io::read(BASE + CLO) - last;
+            let counts = if io::read(BASE + CS) & CS_M1 != 0 { period } else { counts };
+            counts / (TIMCLK / 1000000)
+        }
+    }
+
+    /// Microseconds since the counter was reset at power on
+    pub fn now_us() -> u64
+    {
+        unsafe {
+            // Reread if the low word wraps between the two loads
+            loop {
+                let hi = io::read(BASE + CHI);
+                let lo = io::read(BASE + CLO);
+                if io::read(BASE + CHI) == hi {
+                    return (hi as u64 << 32) | lo as u64;
+                }
+            }
+        }
+    }
+
+    unsafe fn timer_interrupt(_ : u8)
+    {
+        if io::read(BASE + CS) & CS_M1 != 0 {
+            io::wh(BASE + CS, CS_M1);
+            // Compare only matches on equality, so if ticks were missed
+            // the next one must still be in the future
+            let now = io::read(BASE + CLO);
+            last += period;
+            if now - last >= period {
+                last = now;
+            }
+            io::wh(BASE + C1, last + period);
+            match tick_handler {
+                Some(f) => f(),
+                None => ()
+            }
+        }
+    }
+}
+
+/// driver::arm1176jzf_s::serial
+/// The PL011 (UART0) and the AUX mini UART (UART1). Both use GPIO 14 and
+/// 15, so only the console port is brought up.
+// See BCM2835 ARM Peripherals, chapter 2 (AUX) and 13 (PL011)
+pub mod serial{
+    use core::option::{Option, Some, None};
+    use kernel::serial::*;
+    use platform::cpu::interrupt;
+    use platform::drivers::primecell_uart;
+    use platform::drivers::primecell_uart::PL011;
+    use platform::io;
+    use super::gpio;
+
+    pub static COUNT : uint = 2;
+    static NAMES : [&'static str, ..2] = ["uart0", "uart1"];
+
+    static TXD_PIN : uint = 14;
+    static RXD_PIN : uint = 15;
+
+    pub static mut UART0 : PL011 = pl011!(0x20201000, super::UART_CLK, 57);
+    pub static mut UART1 : MiniUart = MiniUart {
+        rate : 0,
+        buffer : [0, .. MINI_BUF_SZ],
+        buf_head : 0,
+        buf_count : 0,
+        handlers : [None, .. primecell_uart::MAX_HANDLERS],
+    };
+
+    /// Port `i`: 0 is the PL011, 1 the mini UART
+    pub fn get(i : uint) -> Option<&'static mut Serial>
+    {
+        unsafe {
+            match i {
+                0 => Some(&mut UART0 as &'static mut Serial),
+                1 => Some(&mut UART1 as &'static mut Serial),
+                _ => None
+            }
+        }
+    }
+
+    /// Device name of port `i`, as under /dev
+    pub fn name(i : uint) -> Option<&'static str>
+    {
+        if i < COUNT { Some(NAMES[i]) } else { None }
+    }
+
+    /// Connect GPIO 14 and 15 to port `i`. They start out on UART0; the
+    /// mini UART can only take them while UART0 is closed, and hands them
+    /// back when it closes.
+    pub fn route(i : uint)
+    {
+        let mode = if i == 0 { gpio::ALT0 } else { gpio::ALT5 };
+        unsafe {
+            gpio::Pin::get(TXD_PIN).get().setMode(mode);
+            gpio::Pin::get(RXD_PIN).get().setMode(mode);
+        }
+    }
+
+    /// Polled write to the console port that works with interrupts
+    /// masked, for reports from exception handlers. UART0 until the mini
+    /// UART is open, since the pins stay with it until then.
+    pub fn putc_raw(c : u8)
+    {
+        unsafe {
+            if console_index() == 1 && UART1.isOpen()
+            {
+                while io::read(AUX_MU_LSR) & LSR_TX_EMPTY == 0 {}
+                io::wh(AUX_MU_IO, c as u32);
+            }
+            else
+            {
+                primecell_uart::putc_raw(UART0.base, c);
+            }
+        }
+    }
+
+    static AUX_IRQ          : u8  = 29;
+    static AUX_ENABLES      : u32 = 0x20215004;
+    static AUX_MU_IO        : u32 = 0x20215040; // Data
+    static AUX_MU_IER       : u32 = 0x20215044; // Interrupt enable
+    static AUX_MU_IIR       : u32 = 0x20215048; // Interrupt identify / FIFO clear
+    static AUX_MU_LCR       : u32 = 0x2021504C; // Line control
+    static AUX_MU_MCR       : u32 = 0x20215050; // Modem control
+    static AUX_MU_LSR       : u32 = 0x20215054; // Line status
+    static AUX_MU_CNTL      : u32 = 0x20215060; // Extra control
+    static AUX_MU_BAUD      : u32 = 0x20215068;
+
+    static ENABLE_MINI_UART : u32 = 1 << 0;
+    static IER_RX           : u32 = 1 << 0;
+    static IIR_CLEAR_FIFOS  : u32 = 0xC6;
+    static LCR_8BIT         : u32 = 3;
+    static LSR_DATA_READY   : u32 = 1 << 0;
+    static LSR_OVERRUN      : u32 = 1 << 1;
+    static LSR_TX_EMPTY     : u32 = 1 << 5;
+    static LSR_TX_IDLE      : u32 = 1 << 6;
+    static CNTL_RX_TX       : u32 = 3;
+
+    static MINI_BUF_SZ : uint = 1024;
+
+    /// The AUX mini UART: 8 data bits, no parity, one stop bit, and an
+    /// 8-byte FIFO each way. Transmit is polled.
+    pub struct MiniUart {
+        rate : baud,
+        buffer : [u8, .. MINI_BUF_SZ],
+        buf_head : uint,
+        buf_count : uint,
+        handlers : [Option<serialReceiveHandler>, .. primecell_uart::MAX_HANDLERS],
+    }
+
+    impl Serial for MiniUart {
+        /// Initialize device and begin transmission. Returns true if device successfully opened.
+        fn open(&mut self, r : u32) -> bool
+        {
+            if r == 0
+            {
+                return false;
+            }
+            // baud = core clock / (8 * (AUX_MU_BAUD + 1))
+            let div = (super::CORE_CLK / 8 + r / 2) / r;
+            if div == 0 || div > 0x10000
+            {
+                return false;
+            }
+            // Taking the pins would cut off the console
+            if unsafe { UART0.isOpen() }
+            {
+                return false;
+            }
+            route(1);
+            unsafe {
+                io::ws(AUX_ENABLES, ENABLE_MINI_UART);
+                io::wh(AUX_MU_CNTL, 0);
+                io::wh(AUX_MU_IER, 0);
+                io::wh(AUX_MU_LCR, LCR_8BIT);
+                io::wh(AUX_MU_MCR, 0);
+                io::wh(AUX_MU_IIR, IIR_CLEAR_FIFOS);
+                io::wh(AUX_MU_BAUD, div - 1);
+                io::wh(AUX_MU_IER, IER_RX);
+                io::wh(AUX_MU_CNTL, CNTL_RX_TX);
+                interrupt::register_irq(AUX_IRQ, miniUartInterrupt);
+            }
+            self.rate = r;
+            self.buf_head = 0;
+            self.buf_count = 0;
+            true
+        }
+
+        fn isOpen(&self) -> bool
+        {
+            self.rate != 0
+        }
+
+        /// End transmission, close device. Returns true if device is closed after operation.
+        fn close(&mut self) -> bool
+        {
+            if self.isOpen()
+            {
+                self.flush();
+                unsafe {
+                    io::wh(AUX_MU_IER, 0);
+                    io::wh(AUX_MU_CNTL, 0);
+                    io::wh(AUX_ENABLES, io::read(AUX_ENABLES) & !ENABLE_MINI_UART);
+                    interrupt::unregister_irq(AUX_IRQ);
+                }
+                route(0);
+            }
+            self.rate = 0;
+            self.buf_head = 0;
+            self.buf_count = 0;
+            true
+        }
+
+        /// Number of bytes available to read
+        fn available(&self) -> uint
+        {
+            self.buf_count
+        }
+
+        /// Read up to length bytes into buffer. Return number of bytes read.
+        fn readBuf(&mut self, buffer : &mut [u8], length : uint) -> uint
+        {
+            let mut i = 0;
+            while (i < length && self.buf_count > 0)
+            {
+                self.read(&mut buffer[i]);
+                i += 1;
+            }
+            i
+        }
+
+        /// Read one character into buffer. Return number of bytes read.
+        fn read(&mut self, c : &mut u8) -> uint
+        {
+            let cpsr = interrupt::disable();
+            let n = if self.buf_count == 0
+            {
+                0
+            }
+            else
+            {
+                *c = self.buffer[self.buf_head];
+                self.buf_head = (self.buf_head + 1) % MINI_BUF_SZ;
+                self.buf_count -= 1;
+                1
+            };
+            interrupt::restore(cpsr);
+            n
+        }
+
+        /// Write a single byte, waiting for room in the FIFO. Return number
+        /// of bytes written.
+        fn write(&mut self, c : u8) -> uint
+        {
+            if !self.isOpen()
+            {
+                return 0;
+            }
+            unsafe {
+                while io::read(AUX_MU_LSR) & LSR_TX_EMPTY == 0 {}
+                io::wh(AUX_MU_IO, c as u32);
+            }
+            1
+        }
+
+        /// Write a buffer of bytes. Return number of bytes written.
+        fn writeBuf(&mut self, buffer : &[u8], length : uint) -> uint
+        {
+            let mut i = 0;
+            while (i < length)
+            {
+                if self.write(buffer[i]) == 0
+                {
+                    break;
+                }
+                i += 1;
+            }
+            i
+        }
+
+        /// Wait for the transmitter to go idle.
+        fn flush(&mut self) -> uint
+        {
+            if self.isOpen()
+            {
+                unsafe {
+                    while io::read(AUX_MU_LSR) & LSR_TX_IDLE == 0 {}
+                }
+            }
+            0
+        }
+
+        /// Callback on new data available.
+        fn addReceiveHandler(&mut self, newHandler : serialReceiveHandler) -> Option<uint>
+        {
+            let cpsr = interrupt::disable();
+            let mut handle = None;
+            let mut i = 0;
+            while i < primecell_uart::MAX_HANDLERS
+            {
+                match self.handlers[i]
+                {
+                    None => {
+                        self.handlers[i] = Some(newHandler);
+                        handle = Some(i);
+                        break;
+                    }
+                    Some(_) => i += 1
+                }
+            }
+            interrupt::restore(cpsr);
+            handle
+        }
+
+        fn removeReceiveHandler(&mut self, handle : uint)
+        {
+            if handle < primecell_uart::MAX_HANDLERS
+            {
+                let cpsr = interrupt::disable();
+                self.handlers[handle] = None;
+                interrupt::restore(cpsr);
+            }
+        }
+
+        /// Remove all receive handlers
+        fn clearReceiveHandlers(&mut self)
+        {
+            let cpsr = interrupt::disable();
+            let mut i = 0;
+            while i < primecell_uart::MAX_HANDLERS
+            {
+                self.handlers[i] = None;
+                i += 1;
+            }
+            interrupt::restore(cpsr);
+        }
+    }
+
+    impl MiniUart
+    {
+        /// Buffer a received byte, dropping the oldest if the buffer is
+        /// full, and hand it to the handlers
+        fn receive(&mut self, c : u8)
+        {
+            if self.buf_count == MINI_BUF_SZ
+            {
+                self.buf_head = (self.buf_head + 1) % MINI_BUF_SZ;
+                self.buf_count -= 1;
+            }
+            self.buffer[(self.buf_head + self.buf_count) % MINI_BUF_SZ] = c;
+            self.buf_count += 1;
+            let mut i = 0;
+            while i < primecell_uart::MAX_HANDLERS
+            {
+                match self.handlers[i]
+                {
+                    Some(f) => f(c),
+                    None => ()
+                }
+                i += 1;
+            }
+        }
+    }
+
+    /// Reading the data register clears the receive interrupt
+    unsafe fn miniUartInterrupt(_ : u8)
+    {
+        loop
+        {
+            let lsr = io::read(AUX_MU_LSR);
+            if lsr & LSR_OVERRUN != 0
+            {
+                kwarn!("uart1: receive overrun");
+            }
+            if lsr & LSR_DATA_READY == 0
+            {
+                break;
+            }
+            UART1.receive(io::read(AUX_MU_IO) as u8);
+        }
+    }
+}
+
+/// driver::arm1176jzf_s::gpio
+/// The 54 BCM2835 GPIO pins: function select, levels, pull-up/down and
+/// event detection. Pins with a callback raise GPIO interrupts.
+// See BCM2835 ARM Peripherals, chapter 6
 pub mod gpio{
     use core::option::{Option, Some, None};
+    use platform::cpu::interrupt;
     use super::super::io::*;
 
     pub static mut BASE : u32 = 0x20200000;
@@ -159,6 +1171,27 @@ pub mod gpio{
     static OUTPUT_SET : u32 = 0x1C;
     static OUTPUT_CLR : u32 = 0x28;
     static OUTPUT_LVL : u32 = 0x34;
+    static EVENT_STATUS : u32 = 0x40;
+    static PULL_CTRL : u32 = 0x94;
+    static PULL_CLK : u32 = 0x98;
+
+    /// gpio_int[3], raised for events on any pin. gpio_int[0-2] (lines
+    /// 49-51) cover pins 0-27, 28-45 and 46-53, which doesn't line up with
+    /// the two event status registers.
+    static IRQ_ALL : u8 = 52;
+
+    /// Callback for an event on a pin, given the pin number. Runs in
+    /// interrupt context.
+    pub type PinHandler = fn(uint);
+
+    static mut handlers : [Option<PinHandler>, ..PINID_MAX] = [None, ..PINID_MAX];
+    static mut irq_registered : bool = false;
+    /// Events on pins without a handler, taken out of the status registers
+    /// by the interrupt so it stops firing, and kept for `event()`
+    static mut latched : [u32, ..2] = [0, 0];
+    /// Levels last driven on output pins; GPLEV reads the pad, which a
+    /// load can pull away from what was written
+    static mut driven : [u32, ..2] = [0, 0];
 
     pub struct Pin {
         id : uint
@@ -168,12 +1201,9 @@ pub mod gpio{
 //trait SPIPin : Pin;
     impl Pin{
         pub fn get(no : uint) -> Option<Pin>{
-            match no {
-                0 .. PINID_MAX => Some(Pin{id : no}),
-                _ => None
-            }
+            if no < PINID_MAX { Some(Pin{id : no}) } else { None }
         }
-       
+
         pub unsafe fn setMode(&self, mode : pin_mode) -> bool{
             // TODO: Validate modes on a per-pin basis
 
@@ -197,36 +1227,126 @@ pub mod gpio{
                 OUTPUT => Some({
                     let bank = BASE // base GPIO address
                         + if(value){ OUTPUT_SET }else{ OUTPUT_CLR } // Which bank: set or clear
-                        + if(self.id > 31){ 4 }else{ 0 }              // Second register if pin is in second bank
+                        + self.bank_offset()                        // Second register if pin is in second bank
                         ;
-                    let mask = 1 << (self.id % 32);
-                    wh(bank, mask);
+                    wh(bank, self.mask());
+                    if value {
+                        driven[self.id / 32] |= self.mask();
+                    } else {
+                        driven[self.id / 32] &= !self.mask();
+                    }
                     true
                 }),
                 _ => None
             }
         }
 
+        /// Input pins report the pad level, output pins the level last
+        /// written to them.
         pub unsafe fn read(&self) -> Option<bool>{
             match self.getMode().get() {
-                OUTPUT | INPUT => Some({
-                    let bank = BASE // base GPIO address
-                        + OUTPUT_LVL
-                        + if(self.id > 31){ 4 }else{ 0 }              // Second register if pin is in second bank
-                        ;
-                    let mask = 1 << (self.id % 32);
-                    
-                    if(0 == mask & read(bank)){
-                        false
-                    }else{
-                        true
-                    }
-                    }),
+                INPUT => Some(read(BASE + OUTPUT_LVL + self.bank_offset()) & self.mask() != 0),
+                OUTPUT => Some(driven[self.id / 32] & self.mask() != 0),
                 _ => None
             } // match
         } // read
+
+        /// Set the pin's internal pull resistor. The control signal has to
+        /// be held for 150 cycles on each side of clocking it into the pin.
+        pub unsafe fn setPull(&self, pull : pull_mode){
+            wh(BASE + PULL_CTRL, pull as u32);
+            settle();
+            wh(BASE + PULL_CLK + self.bank_offset(), self.mask());
+            settle();
+            wh(BASE + PULL_CTRL, 0);
+            wh(BASE + PULL_CLK + self.bank_offset(), 0);
+        }
+
+        /// Turn detection of `kind` on or off. Detected events latch in
+        /// the event status register until cleared.
+        pub unsafe fn detect(&self, kind : event_kind, on : bool){
+            let reg = BASE + kind as u32 + self.bank_offset();
+            let prev = read(reg);
+            wh(reg, if on { prev | self.mask() } else { prev & !self.mask() });
+        }
+
+        /// True if an enabled event has been detected since the last
+        /// `clearEvent`
+        pub unsafe fn event(&self) -> bool{
+            (read(BASE + EVENT_STATUS + self.bank_offset()) | latched[self.id / 32]) & self.mask() != 0
+        }
+
+        pub unsafe fn clearEvent(&self){
+            let irq = interrupt::disable();
+            wh(BASE + EVENT_STATUS + self.bank_offset(), self.mask());
+            latched[self.id / 32] &= !self.mask();
+            interrupt::restore(irq);
+        }
+
+        /// Call `handler` from the GPIO interrupt whenever one of the
+        /// events enabled with `detect` happens on this pin.
+        pub unsafe fn onEvent(&self, handler : PinHandler) -> bool{
+            self.clearEvent();
+            handlers[self.id] = Some(handler);
+            if !irq_registered {
+                irq_registered = interrupt::register_irq(IRQ_ALL, gpioInterrupt);
+            }
+            irq_registered
+        }
+
+        /// Drop the pin's callback and stop detecting events on it.
+        pub unsafe fn removeEvent(&self){
+            self.detect(RISING_EDGE, false);
+            self.detect(FALLING_EDGE, false);
+            self.detect(HIGH_LEVEL, false);
+            self.detect(LOW_LEVEL, false);
+            self.clearEvent();
+            handlers[self.id] = None;
+        }
+
+        fn bank_offset(&self) -> u32{
+            if self.id >= 32 { 4 } else { 0 }
+        }
+
+        fn mask(&self) -> u32{
+            1 << (self.id % 32)
+        }
     } //impl Pin
 
+    /// Wait out the pull-up/down setup and hold times
+    fn settle(){
+        let mut i = 0;
+        while i < 150 {
+            unsafe { asm!("nop"); }
+            i += 1;
+        }
+    }
+
+    /// Acknowledge the events on pins with a handler in both banks and
+    /// call those handlers. Events on other pins move to `latched`, where
+    /// `event()` still sees them.
+    unsafe fn gpioInterrupt(_ : u8){
+        let mut bank = 0;
+        while bank < 2 {
+            let status = BASE + EVENT_STATUS + 4 * bank as u32;
+            let pending = read(status);
+            wh(status, pending);
+
+            let mut bit = 0;
+            while bit < 32 {
+                let pin = bank * 32 + bit;
+                if pending & (1 << bit) != 0 && pin < PINID_MAX {
+                    match handlers[pin] {
+                        Some(h) => h(pin),
+                        None => latched[bank] |= 1 << bit
+                    }
+                }
+                bit += 1;
+            }
+            bank += 1;
+        }
+    }
+
     /// IO mode for GPIO pins
     #[repr(C)]
     pub enum pin_mode{
@@ -254,4 +1374,22 @@ pub mod gpio{
             }
         }
     }
+
+    /// Internal resistor on a pin, as written to GPPUD
+    #[repr(C)]
+    pub enum pull_mode{
+        PULL_NONE = 0,
+        PULL_DOWN = 1,
+        PULL_UP = 2
+    }
+
+    /// Events a pin can detect; the value is the offset of the bank 0
+    /// enable register
+    #[repr(C)]
+    pub enum event_kind{
+        RISING_EDGE = 0x4C,
+        FALLING_EDGE = 0x58,
+        HIGH_LEVEL = 0x64,
+        LOW_LEVEL = 0x70
+    }
 }
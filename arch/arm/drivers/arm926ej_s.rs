@@ -1,17 +1,98 @@
 /* drivers::arm926ej_s */
 
 use core::mem;
+use core::option::{Option, Some, None};
 
 use kernel;
 use kernel::screen::*;
-use kernel::sgash::SGASH;
+use kernel::board::Board;
+use kernel::device;
+use kernel::device::Driver;
+use kernel::fs::devfs;
+use kernel::serial::Serial;
+use super::primecell_probe;
+use super::pl050::PL050;
 use core::mem::transmute;
 
 /* http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dui0225d/BBABEGGE.html */
 /* http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dui0225d/BBABEGGE.html */
+/// Peripheral window: system registers, CLCD, VIC, timers, UARTs
+pub static IO_BASE : u32 = 0x10000000;
+pub static IO_SIZE : u32 = 0x00200000;
+
 static VIC_INT          : *mut u32 = (0x10140000) as *mut u32;
 static VIC_INT_ENABLE   : *mut u32 = (0x10140000 + 0x10) as *mut u32;
 static VIC_INT_DISABLE  : *mut u32 = (0x10140000 + 0x14) as *mut u32; // "enable clear"
+static VIC_VECT_ADDR    : *mut u32 = (0x10140000 + 0x30) as *mut u32;
+
+static SIC_STATUS       : *mut u32 = (0x10003000) as *mut u32;
+static SIC_ENSET        : *mut u32 = (0x10003000 + 0x08) as *mut u32;
+static SIC_ENCLR        : *mut u32 = (0x10003000 + 0x0C) as *mut u32;
+
+/// drivers::arm926ej_s::irq
+/// PrimeCell PL190 vectored interrupt controller, with the board's
+/// secondary controller (SIC) cascaded on its line 31. SIC line n is
+/// numbered 32 + n here.
+// See http://infocenter.arm.com/help/topic/com.arm.doc.ddi0181e/DDI0181.pdf
+// and http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dui0224i/Bbaigdbi.html
+pub mod irq
+{
+    use core::option::{Option, Some, None};
+    use core::mem::{volatile_load, volatile_store};
+    use core::i32::cttz32;
+
+    pub static NR_IRQS : uint = 64;
+
+    /// VIC line the SIC's output drives
+    static SIC_LINE : u8 = 31;
+    /// First line number belonging to the SIC
+    pub static SIC_FIRST : u8 = 32;
+
+    pub unsafe fn enable(line : u8)
+    {
+        if line < SIC_FIRST {
+            volatile_store(super::VIC_INT_ENABLE, 1 << line);
+        } else if (line as uint) < NR_IRQS {
+            volatile_store(super::SIC_ENSET, 1 << (line - SIC_FIRST));
+            volatile_store(super::VIC_INT_ENABLE, 1 << SIC_LINE);
+        }
+    }
+
+    /// Lines on the SIC are masked there; the cascade stays enabled.
+    pub unsafe fn disable(line : u8)
+    {
+        if line < SIC_FIRST {
+            volatile_store(super::VIC_INT_DISABLE, 1 << line);
+        } else if (line as uint) < NR_IRQS {
+            volatile_store(super::SIC_ENCLR, 1 << (line - SIC_FIRST));
+        }
+    }
+
+    /// Lowest numbered line with an active, unmasked request
+    pub unsafe fn next_pending() -> Option<u8>
+    {
+        let status = volatile_load(super::VIC_INT as *u32);
+        if status == 0 {
+            return None;
+        }
+        let line = cttz32(status as i32) as u8;
+        if line != SIC_LINE {
+            return Some(line);
+        }
+        let sic = volatile_load(super::SIC_STATUS as *u32);
+        if sic == 0 {
+            None
+        } else {
+            Some(SIC_FIRST + cttz32(sic as i32) as u8)
+        }
+    }
+
+    /// Signal end of interrupt to the VIC. Sources are cleared by their handlers.
+    pub unsafe fn ack()
+    {
+        volatile_store(super::VIC_VECT_ADDR, 0);
+    }
+}
 
 pub mod screen
 {
@@ -56,47 +137,39 @@ pub mod screen
 
         fn setResolution(&mut self, res : Resolution) -> Resolution
         {
-            self.SCREEN_WIDTH = res.w as u32;
-            self.SCREEN_HEIGHT = res.h as u32;
-            /*unsafe {
-                sgash::init()
-            };*/
             /* For the following magic values, see 
              * http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dui0225d/CACHEDGD.html
+             * Modes without timings here fall back to 640x480.
              */
 
-            match res {
-                WVGA2 => unsafe {
-                    // 800x600
-                    ws(0x10000010, 0x2CAC);
-                    ws(0x10120000, 0x1313A4C4);
-                    ws(0x10120004, 0x0505F657);
-                    ws(0x10120008, 0x071F1800);
-
-                    /* See http://forum.osdev.org/viewtopic.php?p=195000 */
-                    ws(0x10120010, self.START_ADDR);
-                    
-                    /* See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0161e/I911024.html */
-                    ws(0x10120018, 0x82B);
-                },               
-                /*#[allow(unreachable_code)]                      
-                VGA => unsafe {
+            match (res.w, res.h) {
+                (800, 600) => unsafe {
+                    wh(0x10000010, 0x2CAC);
+                    wh(0x10120000, 0x1313A4C4);
+                    wh(0x10120004, 0x0505F657);
+                    wh(0x10120008, 0x071F1800);
+                    self.SCREEN_WIDTH = 800;
+                    self.SCREEN_HEIGHT = 600;
+                },
+                _ => unsafe {
                     // 640x480
                     // See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dui0225d/CACCCFBF.html
-                    ws(0x10000010, 0x2C77);
-                    ws(0x10120000, 0x3F1F3F9C);
-                    ws(0x10120004, 0x090B61DF);
-                    ws(0x10120008, 0x067F1800);
+                    wh(0x10000010, 0x2C77);
+                    wh(0x10120000, 0x3F1F3F9C);
+                    wh(0x10120004, 0x090B61DF);
+                    wh(0x10120008, 0x067F1800);
+                    self.SCREEN_WIDTH = 640;
+                    self.SCREEN_HEIGHT = 480;
+                }
+            } // match resolution
 
-                    /* See http://forum.osdev.org/viewtopic.php?p=195000 */
-                    ws(0x10120010, self.START_ADDR);
+            unsafe {
+                /* See http://forum.osdev.org/viewtopic.php?p=195000 */
+                wh(0x10120010, self.START_ADDR);
 
-                    /* See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0161e/I911024.html */
-                    ws(0x10120018, 0x82B);
-                },
-                _ => abort() 
-                    */
-            } // match resolution                
+                /* See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0161e/I911024.html */
+                wh(0x10120018, 0x82B);
+            }
 
             self.getResolution()
         } // setResolution
@@ -300,195 +373,286 @@ pub mod screen
 }
 
 
-pub unsafe fn init(r : Resolution)
+static UART0_DEV : Driver = Driver {
+    name : "uart0", compatible : "arm,pl011", base : 0x101f1000, id : 0,
+    caps : device::SERIAL, probe : primecell_probe, init : uart_init, shutdown : Some(uart_shutdown)
+};
+static UART1_DEV : Driver = Driver {
+    name : "uart1", compatible : "arm,pl011", base : 0x101f2000, id : 1,
+    caps : device::SERIAL, probe : primecell_probe, init : uart_init, shutdown : Some(uart_shutdown)
+};
+static UART2_DEV : Driver = Driver {
+    name : "uart2", compatible : "arm,pl011", base : 0x101f3000, id : 2,
+    caps : device::SERIAL, probe : primecell_probe, init : uart_init, shutdown : Some(uart_shutdown)
+};
+static KBD0 : Driver = Driver {
+    name : "kbd0", compatible : "arm,pl050", base : 0x10006000, id : 0,
+    caps : device::INPUT, probe : primecell_probe, init : kbd_init, shutdown : Some(kbd_shutdown)
+};
+static FB0 : Driver = Driver {
+    name : "fb0", compatible : "arm,pl110", base : 0x10120000, id : 0,
+    caps : device::CANVAS, probe : primecell_probe, init : fb_init, shutdown : Some(fb_shutdown)
+};
+
+/// Handle of the log sink drawing on the screen
+static mut screen_sink : Option<uint> = None;
+
+pub fn init()
 {
-    let cv = &mut screen::Screen0;
-    cv.sync();
-    cv.setResolution(r);
-    cv.set_bg(kernel::screen::ARGBPixel(0x00, 0x22, 0x2C, 0x38));
-    cv.set_fg(kernel::screen::ARGBPixel(0x00, 0xFA, 0xFC, 0xFF));
-    cv.set_cursor_color(kernel::screen::ARGBPixel(0x00, 0xFA, 0xFC, 0xFF));
-    cv.fill_bg();
+    device::register(&UART0_DEV);
+    device::register(&UART1_DEV);
+    device::register(&UART2_DEV);
+    device::register(&FB0);
+    device::register(&KBD0);
 }
 
-static UART_CLK : uint = 24000000; // 24 MHz
+/// UART0 carries the console from boot; the others are opened on first use.
+fn uart_init(d : &Driver) -> bool
+{
+    match serial::get(d.id) {
+        Some(port) => {
+            if d.id == 0 && !port.open(kernel::serial::console_baud()) {
+                return false;
+            }
+            devfs::register(d.name, devfs::SerialDev(port))
+        }
+        None => false
+    }
+}
 
-pub mod serial
+fn uart_shutdown(d : &Driver)
 {
-    use kernel::serial::*;
-    use kernel;
-    use platform::cpu::interrupt;
-    use core::mem::{volatile_load, volatile_store};
-    use platform::io;
+    devfs::unregister(d.name);
+    match serial::get(d.id) {
+        Some(port) => { port.close(); }
+        None => ()
+    }
+}
 
-    // TODO Use resizable buffers
-    static UART_BUF_SZ : uint = 1024;
+/// Keys typed in QEMU's window go to `drivers::keydown`.
+fn kbd_init(_ : &Driver) -> bool
+{
+    unsafe { KMI0.open() }
+}
 
-    // See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dui0224i/Bbabegge.html
+fn kbd_shutdown(_ : &Driver)
+{
+    unsafe { KMI0.close(); }
+}
 
+fn fb_init(_ : &Driver) -> bool
+{
+    unsafe {
+        let cv = &mut screen::Screen0;
+        let res = cv.setResolution(kernel::screen::requested());
+        cv.set_bg(kernel::screen::ARGBPixel(0x00, 0x22, 0x2C, 0x38));
+        cv.set_fg(kernel::screen::ARGBPixel(0x00, 0xFA, 0xFC, 0xFF));
+        cv.set_cursor_color(kernel::screen::ARGBPixel(0x00, 0xFA, 0xFC, 0xFF));
+        cv.fill_bg();
+
+        kinfo!("screen0: ", res.w, "x", res.h);
+        screen_sink = kernel::log::add_sink(log_to_screen);
+        devfs::register("fb0", devfs::CanvasDev(cv as &'static mut TerminalCanvas))
+    }
+}
 
-    struct UART{
-        priv base : *mut u32,
-        priv IMSC : *mut u32,
-        priv IRQ : u8,
-        priv rate : baud,
-        priv buffer : [u8, .. UART_BUF_SZ],
-        priv buf_head : uint,
-        priv buf_count : uint,
+fn fb_shutdown(d : &Driver)
+{
+    devfs::unregister(d.name);
+    unsafe {
+        match screen_sink {
+            Some(h) => kernel::log::remove_sink(h),
+            None => ()
+        }
+        screen_sink = None;
     }
+}
 
-    // See http://infocenter.arm.com/help/topic/com.arm.doc.ddi0183f/DDI0183.pdf
+/// QEMU's Versatile/PB with an ARM926EJ-S
+pub struct VersatilePB;
 
-    pub static mut UART0 : UART = UART {
-        base : 0x101f1000 as *mut u32,
-        IMSC :  (0x101f1000 + 0x038) as *mut u32,
-        IRQ : 12,
-        /* TODO receive handlers */
-        rate : 0,
-        buffer : [0, .. UART_BUF_SZ],
-        buf_head : 0,
-        buf_count : 0,
-    }; 
+pub static BOARD : VersatilePB = VersatilePB;
 
-    impl Serial for UART{
+impl Board for VersatilePB
+{
+    fn name(&self) -> &'static str { "versatilepb" }
+    unsafe fn init(&self) { init() }
 
-        /// Initialize device and begin transmission. Returns true if device successfully opened.
-        // TODO allow for multiple baud rates
-        #[allow(unused_variable)]
-        fn open(&mut self, r : u32) -> bool
-        {
-            unsafe{
-                // enable UART0 IRQ [4]
-                *super::VIC_INT_ENABLE = 1 << self.IRQ;
-                // enable RXIM interrupt (interrupt on receive)
-                /*
-                 * See
-                 * http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0183f/I54603.html
-                 */
-                *self.IMSC = 1 << 4;
-                kernel::int_table.map(|t| {
-                    t.enable(interrupt::IRQ, UART0_receiveInterrupt);
-                });
+    fn io_window(&self) -> (u32, u32) { (IO_BASE, IO_SIZE) }
+    fn default_ram(&self) -> Option<(uint, uint)> { Some((0, 0x02000000)) } // -m 32M in the Makefile
+
+    fn serial_count(&self) -> uint { serial::COUNT }
+    fn serial(&self, i : uint) -> Option<&'static mut Serial> { serial::get(i) }
+    fn serial_name(&self, i : uint) -> Option<&'static str> { serial::name(i) }
+    fn putc_raw(&self, c : u8) { serial::putc_raw(c) }
+
+    fn display(&self) -> Option<&'static mut TerminalCanvas>
+    {
+        unsafe {
+            if screen::Screen0.getResolution().w == 0 {
+                None
+            } else {
+                Some(&mut screen::Screen0 as &'static mut TerminalCanvas)
             }
-            self.buf_head = 0;
-            self.buf_count = 0;
-            false
         }
+    }
 
-        fn isOpen(&self) -> bool
-        {
-            self.rate == 0
-        }
+    fn nr_irqs(&self) -> uint { irq::NR_IRQS }
+    unsafe fn irq_enable(&self, line : u8) { irq::enable(line) }
+    unsafe fn irq_disable(&self, line : u8) { irq::disable(line) }
+    unsafe fn irq_next_pending(&self) -> Option<u8> { irq::next_pending() }
+    unsafe fn irq_ack(&self) { irq::ack() }
 
-        /// End transmission, close device. Returns true if device is closed after operation.
-        fn close(&mut self) -> bool
-        {
-            self.rate = 0;
-            self.buf_head = 0;
-            self.buf_count = 0;
-            true
-        }
+    fn timer_start(&self, hz : u32, handler : fn()) -> bool { timer::start(hz, handler) }
+    fn timer_stop(&self) { timer::stop() }
+    fn timer_elapsed_us(&self) -> u32 { timer::elapsed_us() }
+}
 
-        /// Number of bytes available to read
-        fn available(&self) -> uint
-        {
-            self.buf_count
+/// drivers::arm926ej_s::timer
+/// PrimeCell SP804 dual timer. Timer 0 provides the system tick.
+// See http://infocenter.arm.com/help/topic/com.arm.doc.ddi0271d/DDI0271.pdf
+pub mod timer
+{
+    use core::option::{Option, Some, None};
+    use platform::cpu::interrupt;
+    use platform::io;
+
+    static SCCTRL   : u32 = 0x101E0000; // System controller
+    static BASE     : u32 = 0x101E2000; // Timers 0 and 1
+    static IRQ      : u8  = 4;
+    pub static TIMCLK : u32 = 1000000; // 1 MHz
+
+    static LOAD     : u32 = 0x00;
+    static VALUE    : u32 = 0x04;
+    static CONTROL  : u32 = 0x08;
+    static INTCLR   : u32 = 0x0C;
+    static RIS      : u32 = 0x10;
+    static MIS      : u32 = 0x14;
+
+    static CTRL_32BIT    : u32 = 1 << 1;
+    static CTRL_INTEN    : u32 = 1 << 5;
+    static CTRL_PERIODIC : u32 = 1 << 6;
+    static CTRL_ENABLE   : u32 = 1 << 7;
+    // Clock timer 0 from TIMCLK rather than the 32 kHz REFCLK
+    static SCCTRL_TIMER0_TIMCLK : u32 = 1 << 15;
+
+    static mut tick_handler : Option<fn()> = None;
+
+    /// Interrupt `hz` times a second, calling `handler` on every tick.
+    pub fn start(hz : u32, handler : fn()) -> bool
+    {
+        if hz == 0 || hz > TIMCLK {
+            return false;
         }
-        
-        /// Read up to length bytes into buffer. Return number of bytes read.
-        fn readBuf(&mut self, buffer : &mut [u8], length : uint) -> uint
-        {
-            let mut i = 0;
-            while (i < length && self.buf_count > 0)
-            {
-                self.read(&mut buffer[i]);
-                i += 1;
+        unsafe {
+            io::wh(BASE + CONTROL, 0);
+            io::ws(SCCTRL, SCCTRL_TIMER0_TIMCLK);
+            tick_handler = Some(handler);
+            io::wh(BASE + LOAD, TIMCLK / hz);
+            io::wh(BASE + INTCLR, 1);
+            if !interrupt::register_irq(IRQ, timer_interrupt) {
+                tick_handler = None;
+                return false;
             }
-            i
+            io::wh(BASE + CONTROL, CTRL_ENABLE | CTRL_PERIODIC | CTRL_INTEN | CTRL_32BIT);
         }
+        true
+    }
 
-        /// Read one character into buffer. Return number of bytes read.
-        fn read(&mut self, c : &mut u8) -> uint
-        {
-            if self.buf_count == 0 
-            {
-                return 0;
-            }
-            else
-            {
-                *c = self.buffer[self.buf_head];
-                self.buf_head += 1;
-                self.buf_count -= 1;
-                return 1;
-            }
+    pub fn stop()
+    {
+        unsafe {
+            io::wh(BASE + CONTROL, 0);
+            interrupt::unregister_irq(IRQ);
+            tick_handler = None;
         }
+    }
 
-        /// Write a single byte. Return number of bytes written.
-        fn write(&self, c : u8) -> uint
-        {
-            unsafe {
-                /*
-                 * We need to include a blank asm call to prevent rustc
-                 * from optimizing this part out
-                 */
-                asm!("");
-                volatile_store(self.base, c as u32);
-            }
-            1
+    /// Microseconds since the last tick the handler has seen. With a tick
+    /// pending the counter has already reloaded, so that period is added.
+    pub fn elapsed_us() -> u32
+    {
+        unsafe {
+            let load = io::read(BASE + LOAD);
+            let mut value = io::read(BASE + VALUE);
+            let counts = if io::read(BASE + RIS) & 1 != 0 {
+                // It may have reloaded just after the first read
+                value = io::read(BASE + VALUE);
+                2 * load - value
+            } else {
+                load - value
+            };
+            counts / (TIMCLK / 1000000)
         }
+    }
 
-        /// Write a buffer of bytes. Return number of bytes written.
-        fn writeBuf(&self, buffer : &[u8], length : uint) -> uint
-        {
-            let mut i = 0;
-            while (i < length)
-            {
-                self.write(buffer[i]);
+    unsafe fn timer_interrupt(_ : u8)
+    {
+        if io::read(BASE + MIS) & 1 != 0 {
+            io::wh(BASE + INTCLR, 1);
+            match tick_handler {
+                Some(f) => f(),
+                None => ()
             }
-            return length;
         }
+    }
+}
 
-        fn flush(&self) -> uint
-        {
-            0
-        }
+/// UARTCLK on the Versatile board
+pub static UART_CLK : u32 = 24000000; // 24 MHz
+/// KMIREFCLK, likewise
+pub static KMI_CLK : u32 = 24000000; // 24 MHz
 
-        /// Callback on new data available.
-        fn addReceiveHandler(&self, newHandler : serialReceiveHandler) -> bool
-        {
-            false
-        }
+/// The keyboard port; its interrupt is SIC line 3
+pub static mut KMI0 : PL050 = pl050!(0x10006000, KMI_CLK, 35);
 
-        /// Remove all receive handlers
-        fn clearReceiveHandlers(&self)
-        {
-            ()
-        }
-    }
-  
-    impl UART
+/// drivers::arm926ej_s::serial
+/// Three PL011s; QEMU connects one to each -serial option, in order.
+// See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dui0224i/Bbabegge.html
+pub mod serial
+{
+    use core::option::{Option, Some, None};
+    use kernel::serial::{Serial, console_index};
+    use platform::drivers::primecell_uart;
+    use platform::drivers::primecell_uart::PL011;
+
+    pub static COUNT : uint = 3;
+    static NAMES : [&'static str, ..3] = ["uart0", "uart1", "uart2"];
+
+    pub static mut UART0 : PL011 = pl011!(0x101f1000, super::UART_CLK, 12);
+    pub static mut UART1 : PL011 = pl011!(0x101f2000, super::UART_CLK, 13);
+    pub static mut UART2 : PL011 = pl011!(0x101f3000, super::UART_CLK, 14);
+
+    /// Port `i`, counting from UART0
+    pub fn get(i : uint) -> Option<&'static mut Serial>
     {
-        fn receive(&mut self, c : u8) -> bool
-        {
-            if(self.buf_count == UART_BUF_SZ)
-            {
-                false
-            }else
-            {
-                self.buffer[(self.buf_head + self.buf_count) % UART_BUF_SZ] = c;
-                self.buf_count += 1;
-                true
+        unsafe {
+            match i {
+                0 => Some(&mut UART0 as &'static mut Serial),
+                1 => Some(&mut UART1 as &'static mut Serial),
+                2 => Some(&mut UART2 as &'static mut Serial),
+                _ => None
             }
         }
     }
 
-#[no_mangle]
-    unsafe fn UART0_receiveInterrupt() 
-    { 
-        let x = io::read(UART0.base as u32) as u8;
-        UART0.receive(x);
-        asm!("  pop {r11, lr}
-                subs pc, r14, #4") // pc = lr - 4
+    /// Device name of port `i`, as under /dev
+    pub fn name(i : uint) -> Option<&'static str>
+    {
+        if i < COUNT { Some(NAMES[i]) } else { None }
+    }
 
+    /// Polled write to the console port that works with interrupts
+    /// masked, for reports from exception handlers.
+    pub fn putc_raw(c : u8)
+    {
+        unsafe {
+            let base = match console_index() {
+                1 => UART1.base,
+                2 => UART2.base,
+                _ => UART0.base
+            };
+            primecell_uart::putc_raw(base, c);
+        }
     }
 }
@@ -3,6 +3,7 @@
 use super::io;
 use core::option::{Option, None};
 use kernel;
+use kernel::board::Board;
 
 // See http://static.rust-lang.org/doc/master/rust.html#conditional-compilation
 
@@ -14,6 +15,11 @@ pub use chip = self::arm1176jzf_s;
 pub use chip = self::arm926ej_s;
 
 
+#[macro_escape]
+pub mod primecell_uart;
+#[macro_escape]
+pub mod pl050;
+
 #[cfg(target_chip = "arm926ej-s")]
 mod arm926ej_s;
 #[cfg(target_chip = "arm1176jzf-s")]
@@ -22,33 +28,33 @@ mod arm1176jzf_s;
 
 
 pub fn init() {
-    // removed 926-specific initialization
-    /*
-     * unsafe {
-        kernel::int_table.map(|t| {
-	// See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.dai0235c/index.html
-            t.enable(interrupt::IRQ, keypress);
-        });
+    let b = board();
+    kinfo!("board: ", b.name());
+    unsafe {
+        b.init();
     }
-    */
+    kernel::device::probe_all();
+}
+
+/// Probe for a PrimeCell peripheral: all of them end their register
+/// block with the same four-byte component ID.
+pub fn primecell_probe(d: &kernel::device::Driver) -> bool {
+    let mut id = 0;
+    let mut i = 0;
     unsafe {
-        chip::init(kernel::screen::Resolutions::VGA);
+        while i < 4 {
+            id |= (io::read(d.base + 0xFF0 + 4 * i) & 0xFF) << (8 * i);
+            i += 1;
+        }
     }
+    id == 0xB105F00D
 }
 
-pub static mut keydown: Option<extern unsafe fn(char)> = None;
-pub static mut read_char: Option<extern fn()->char> = None;
+/// The board this kernel was built for
+pub fn board() -> &'static Board {
+    &chip::BOARD as &'static Board
+}
 
-/*
-#[no_mangle]
-pub unsafe fn keypress() {
-	keydown.map(|f| {
-		let x = (*self::arm926ej_s::serial::UART0.base) as u8 as char;
-		f(x)
-	}
-	);
-    // Exception return instruction. [8]
-    // TODO: better interrupt handler. r11 could change
-    asm!("pop {r11, lr}
-          subs pc, r14, #4") // pc = lr - 4
-}*/
+/// Where keyboard drivers send the characters typed
+pub static mut keydown: Option<fn(char)> = None;
+pub static mut read_char: Option<extern fn()->char> = None;
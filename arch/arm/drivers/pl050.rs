@@ -0,0 +1,332 @@
+/* platform::drivers::pl050 */
+/* ARM PrimeCell PS/2 keyboard/mouse interface (KMI) with a PS/2 keyboard
+ * behind it, translated from scan code set 2 to characters */
+// See http://infocenter.arm.com/help/topic/com.arm.doc.ddi0143c/DDI0143.pdf
+
+use core::option::{Option, Some, None};
+
+use platform::cpu::interrupt;
+use platform::io;
+
+static CR       : u32 = 0x00; // Control
+static STAT     : u32 = 0x04; // Status
+static DATA     : u32 = 0x08; // Received / to transmit
+static CLKDIV   : u32 = 0x0C; // KMIREFCLK divisor
+
+static CR_EN        : u32 = 1 << 2;
+static CR_RXINTREN  : u32 = 1 << 4;
+static STAT_RXFULL  : u32 = 1 << 4;
+static STAT_TXEMPTY : u32 = 1 << 6;
+
+/// The interface wants an 8 MHz reference
+static KMI_REFCLK : u32 = 8000000;
+
+/// Bytes waiting to go to the keyboard
+pub static TX_QUEUE : uint = 8;
+
+// Bytes from the keyboard
+static SC_EXTENDED  : u8 = 0xE0;
+static SC_RELEASE   : u8 = 0xF0;
+static SC_ACK       : u8 = 0xFA;
+static SC_RESEND    : u8 = 0xFE;
+static SC_SELFTEST  : u8 = 0xAA;
+
+// Set 2 make codes of the keys that change state
+static KEY_LSHIFT   : u8 = 0x12;
+static KEY_RSHIFT   : u8 = 0x59;
+static KEY_CTRL     : u8 = 0x14; // E0 14 is the right one
+static KEY_CAPSLOCK : u8 = 0x58;
+static KEY_DELETE   : u8 = 0x71; // after E0; 0x71 alone is keypad '.'
+static KEY_KP_SLASH : u8 = 0x4A; // after E0
+static KEY_KP_ENTER : u8 = 0x5A; // after E0
+
+// Commands to the keyboard
+static CMD_LEDS     : u8 = 0xED;
+static CMD_TYPEMATIC: u8 = 0xF3;
+static LED_CAPSLOCK : u8 = 1 << 2;
+/// Repeat after 500 ms, 10.9 times a second
+static TYPEMATIC    : u8 = 0x2B;
+
+static MOD_LSHIFT   : u8 = 1 << 0;
+static MOD_RSHIFT   : u8 = 1 << 1;
+static MOD_LCTRL    : u8 = 1 << 2;
+static MOD_RCTRL    : u8 = 1 << 3;
+static MOD_CAPSLOCK : u8 = 1 << 4;
+/// Caps lock key is down, so its repeats don't toggle the lock
+static MOD_CAPSKEY  : u8 = 1 << 5;
+
+/// Make codes with an entry in the tables below
+static CODES : uint = 0x80;
+
+/// Characters for set 2 make codes 0x00-0x7F without shift; 0 for keys
+/// that don't produce one
+static PLAIN : [u8, ..CODES] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x60, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x71, 0x31, 0x00, 0x00, 0x00, 0x7A, 0x73, 0x61, 0x77, 0x32, 0x00,
+    0x00, 0x63, 0x78, 0x64, 0x65, 0x34, 0x33, 0x00, 0x00, 0x20, 0x76, 0x66, 0x74, 0x72, 0x35, 0x00,
+    0x00, 0x6E, 0x62, 0x68, 0x67, 0x79, 0x36, 0x00, 0x00, 0x00, 0x6D, 0x6A, 0x75, 0x37, 0x38, 0x00,
+    0x00, 0x2C, 0x6B, 0x69, 0x6F, 0x30, 0x39, 0x00, 0x00, 0x2E, 0x2F, 0x6C, 0x3B, 0x70, 0x2D, 0x00,
+    0x00, 0x00, 0x27, 0x00, 0x5B, 0x3D, 0x00, 0x00, 0x00, 0x00, 0x0D, 0x5D, 0x00, 0x5C, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7F, 0x00, 0x00, 0x31, 0x00, 0x34, 0x37, 0x00, 0x00, 0x00,
+    0x30, 0x2E, 0x32, 0x35, 0x36, 0x38, 0x1B, 0x00, 0x00, 0x2B, 0x33, 0x2D, 0x2A, 0x39, 0x00, 0x00,
+];
+
+/// The same with shift held. Keypad keys are unaffected (num lock on).
+static SHIFTED : [u8, ..CODES] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x7E, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x51, 0x21, 0x00, 0x00, 0x00, 0x5A, 0x53, 0x41, 0x57, 0x40, 0x00,
+    0x00, 0x43, 0x58, 0x44, 0x45, 0x24, 0x23, 0x00, 0x00, 0x20, 0x56, 0x46, 0x54, 0x52, 0x25, 0x00,
+    0x00, 0x4E, 0x42, 0x48, 0x47, 0x59, 0x5E, 0x00, 0x00, 0x00, 0x4D, 0x4A, 0x55, 0x26, 0x2A, 0x00,
+    0x00, 0x3C, 0x4B, 0x49, 0x4F, 0x29, 0x28, 0x00, 0x00, 0x3E, 0x3F, 0x4C, 0x3A, 0x50, 0x5F, 0x00,
+    0x00, 0x00, 0x22, 0x00, 0x7B, 0x2B, 0x00, 0x00, 0x00, 0x00, 0x0D, 0x7D, 0x00, 0x7C, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7F, 0x00, 0x00, 0x31, 0x00, 0x34, 0x37, 0x00, 0x00, 0x00,
+    0x30, 0x2E, 0x32, 0x35, 0x36, 0x38, 0x1B, 0x00, 0x00, 0x2B, 0x33, 0x2D, 0x2A, 0x39, 0x00, 0x00,
+];
+
+pub struct PL050 {
+    base : u32,
+    /// KMIREFCLK in Hz
+    clock : u32,
+    IRQ : u8,
+
+    /// MOD_* bits of the modifiers held and caps lock
+    modifiers : u8,
+    /// An 0xE0 prefix came in
+    extended : bool,
+    /// An 0xF0 prefix came in: the next code is a key going up
+    release : bool,
+
+    /// Bytes for the keyboard. The first has been sent once `waiting` is
+    /// set; each waits for the ACK to the one before it.
+    tx : [u8, ..TX_QUEUE],
+    tx_head : uint,
+    tx_count : uint,
+    waiting : bool,
+}
+
+/// Initializer for a static PL050
+macro_rules! pl050(
+    ($base:expr, $clock:expr, $irq:expr) => (
+        ::platform::drivers::pl050::PL050 {
+            base : $base,
+            clock : $clock,
+            IRQ : $irq,
+            modifiers : 0,
+            extended : false,
+            release : false,
+            tx : [0, .. ::platform::drivers::pl050::TX_QUEUE],
+            tx_head : 0,
+            tx_count : 0,
+            waiting : false,
+        }
+    );
+)
+
+/// The keyboard being listened to, for the interrupt handler
+static mut keyboard : *mut PL050 = 0 as *mut PL050;
+
+impl PL050 {
+    /// Enable the interface and its receive interrupt, then set the
+    /// keyboard's repeat rate. Characters go to `drivers::keydown`.
+    pub fn open(&mut self) -> bool
+    {
+        unsafe {
+            if keyboard as uint != 0 {
+                return false;
+            }
+            io::wh(self.base + CR, 0);
+            io::wh(self.base + CLKDIV, self.clock / KMI_REFCLK - 1);
+            io::wh(self.base + CR, CR_EN | CR_RXINTREN);
+            // Throw away anything left over from before
+            while io::read(self.base + STAT) & STAT_RXFULL != 0 {
+                io::read(self.base + DATA);
+            }
+            self.tx_head = 0;
+            self.tx_count = 0;
+            self.waiting = false;
+            keyboard = self as *mut PL050;
+            if !interrupt::register_irq(self.IRQ, kmiInterrupt) {
+                keyboard = 0 as *mut PL050;
+                io::wh(self.base + CR, 0);
+                return false;
+            }
+        }
+        self.modifiers = 0;
+        self.extended = false;
+        self.release = false;
+        // The keyboard repeats held keys by itself; set how fast
+        self.send(CMD_TYPEMATIC);
+        self.send(TYPEMATIC);
+        true
+    }
+
+    pub fn close(&mut self)
+    {
+        unsafe {
+            if keyboard == self as *mut PL050 {
+                interrupt::unregister_irq(self.IRQ);
+                io::wh(self.base + CR, 0);
+                keyboard = 0 as *mut PL050;
+            }
+        }
+    }
+
+    /// Queue a byte for the keyboard. A command's parameter byte must not
+    /// go out until the keyboard has acknowledged the command, so bytes
+    /// are sent one at a time as the receive interrupt sees each ACK.
+    fn send(&mut self, b : u8)
+    {
+        let irq = interrupt::disable();
+        if self.tx_count < TX_QUEUE {
+            self.tx[(self.tx_head + self.tx_count) % TX_QUEUE] = b;
+            self.tx_count += 1;
+            if !self.waiting {
+                self.transmit();
+            }
+        }
+        interrupt::restore(irq);
+    }
+
+    /// Put the byte at the head of the queue on the wire.
+    fn transmit(&mut self)
+    {
+        unsafe {
+            while io::read(self.base + STAT) & STAT_TXEMPTY == 0 {}
+            io::wh(self.base + DATA, self.tx[self.tx_head] as u32);
+        }
+        self.waiting = true;
+    }
+
+    /// The keyboard answered the byte in flight: drop it on ACK and send
+    /// the next, or send it again on RESEND.
+    fn answered(&mut self, b : u8)
+    {
+        if !self.waiting {
+            return;
+        }
+        self.waiting = false;
+        if b == SC_ACK {
+            self.tx_head = (self.tx_head + 1) % TX_QUEUE;
+            self.tx_count -= 1;
+        }
+        if self.tx_count > 0 {
+            self.transmit();
+        }
+    }
+
+    /// Feed one byte from the keyboard through the set 2 decoder.
+    fn scancode(&mut self, b : u8)
+    {
+        if b == SC_EXTENDED {
+            self.extended = true;
+            return;
+        }
+        if b == SC_RELEASE {
+            self.release = true;
+            return;
+        }
+        if b == SC_ACK || b == SC_RESEND {
+            self.answered(b);
+            return;
+        }
+        if b == SC_SELFTEST {
+            return;
+        }
+        let (extended, release) = (self.extended, self.release);
+        self.extended = false;
+        self.release = false;
+
+        let bit = if b == KEY_LSHIFT && !extended {
+            MOD_LSHIFT
+        } else if b == KEY_RSHIFT {
+            MOD_RSHIFT
+        } else if b == KEY_CTRL {
+            if extended { MOD_RCTRL } else { MOD_LCTRL }
+        } else {
+            0
+        };
+        if bit != 0 {
+            if release {
+                self.modifiers &= !bit;
+            } else {
+                self.modifiers |= bit;
+            }
+            return;
+        }
+        if b == KEY_CAPSLOCK && !extended {
+            if release {
+                self.modifiers &= !MOD_CAPSKEY;
+            } else if self.modifiers & MOD_CAPSKEY == 0 {
+                self.modifiers |= MOD_CAPSKEY;
+                self.modifiers ^= MOD_CAPSLOCK;
+                let leds = if self.modifiers & MOD_CAPSLOCK != 0 { LED_CAPSLOCK } else { 0 };
+                self.send(CMD_LEDS);
+                self.send(leds);
+            }
+            return;
+        }
+        if release {
+            return;
+        }
+
+        let c = if extended {
+            // Of the E0 keys only these type something; the arrows and
+            // the navigation block are dropped
+            if b == KEY_KP_ENTER {
+                '\r' as u8
+            } else if b == KEY_KP_SLASH {
+                '/' as u8
+            } else if b == KEY_DELETE {
+                0x7F
+            } else {
+                0
+            }
+        } else if (b as uint) < CODES {
+            self.translate(b)
+        } else {
+            0
+        };
+        if c != 0 {
+            deliver(c as char);
+        }
+    }
+
+    /// Character for make code `b` under the current modifiers
+    fn translate(&self, b : u8) -> u8
+    {
+        let shift = self.modifiers & (MOD_LSHIFT | MOD_RSHIFT) != 0;
+        let ctrl = self.modifiers & (MOD_LCTRL | MOD_RCTRL) != 0;
+        let caps = self.modifiers & MOD_CAPSLOCK != 0;
+
+        let plain = PLAIN[b as uint];
+        let is_letter = plain >= 'a' as u8 && plain <= 'z' as u8;
+        if ctrl && is_letter {
+            // Ctrl-A is 1, and so on
+            return plain - 'a' as u8 + 1;
+        }
+        // Caps lock only affects letters, and shift undoes it
+        let upper = if is_letter { shift != caps } else { shift };
+        if upper { SHIFTED[b as uint] } else { plain }
+    }
+}
+
+fn deliver(c : char)
+{
+    unsafe {
+        match super::keydown {
+            Some(f) => f(c),
+            None => ()
+        }
+    }
+}
+
+unsafe fn kmiInterrupt(_ : u8)
+{
+    if keyboard as uint == 0 {
+        return;
+    }
+    let base = (*keyboard).base;
+    while io::read(base + STAT) & STAT_RXFULL != 0 {
+        (*keyboard).scancode(io::read(base + DATA) as u8);
+    }
+}
@@ -1,74 +1,169 @@
 /* platform::drivers::primecell_uart */
 /* Implementation of Serial for ARM's PrimeCell UART chip (PL011) */
 // See http://infocenter.arm.com/help/topic/com.arm.doc.ddi0183f/DDI0183.pdf
-//
-use kernel::serial;
-use platform::drivers::chip;
+
+use core::option::{Option, Some, None};
+
+use kernel::serial::*;
+use kernel::log::Hex;
+use platform::cpu::interrupt;
 use platform::io;
 
 // TODO resizable buffers
-static PrimeCell_BUF_SZ : uint = 1024
+pub static PL011_BUF_SZ : uint = 1024;
+
+static MAX_PORTS : uint = 4;
+pub static MAX_HANDLERS : uint = 4;
+
+pub enum Parity {
+    NoParity,
+    EvenParity,
+    OddParity
+}
 
-struct PrimeCell {
-    // NB: Base addresses should be mutable, to allow for re-mapping 
+/// Errors seen on the receive line since the port was opened
+pub struct LineErrors {
+    framing : uint,
+    parity : uint,
+    breaks : uint,
+    overruns : uint
+}
+
+pub struct PL011 {
+    // NB: Base addresses should be mutable, to allow for re-mapping
     base : u32,
-    IRQ : u32,
-    
+    /// UARTCLK in Hz, which the baud rate divisor is derived from
+    clock : u32,
+    IRQ : u8,
+
+    /// Zero while closed
     rate : baud,
+    /// Line format applied by `open`; 8N1 unless changed with `configure`
+    data_bits : u8,
+    parity : Parity,
+    stop_bits : u8,
+
+    errors : LineErrors,
+
+    buffer : [u8, .. PL011_BUF_SZ],
+    buf_head : uint,
+    buf_count : uint,
 
-    priv buffer : [u8, .. PrimeCell_BUF_SZ],
-    priv buf_head : uint,
-    priv buf_count,
+    /// Bytes waiting for room in the transmit FIFO
+    tx_buffer : [u8, .. PL011_BUF_SZ],
+    tx_head : uint,
+    tx_count : uint,
 
-    // TODO proper receive handlers
+    handlers : [Option<serialReceiveHandler>, .. MAX_HANDLERS],
 }
 
-impl Serial for PrimeCell{
+/// A closed PL011 at `base` clocked at `clock` Hz, for static initializers
+macro_rules! pl011(
+    ($base:expr, $clock:expr, $irq:expr) => (
+        ::platform::drivers::primecell_uart::PL011 {
+            base : $base,
+            clock : $clock,
+            IRQ : $irq,
+            rate : 0,
+            data_bits : 8,
+            parity : ::platform::drivers::primecell_uart::NoParity,
+            stop_bits : 1,
+            errors : ::platform::drivers::primecell_uart::LineErrors {
+                framing : 0, parity : 0, breaks : 0, overruns : 0
+            },
+            buffer : [0, .. ::platform::drivers::primecell_uart::PL011_BUF_SZ],
+            buf_head : 0,
+            buf_count : 0,
+            tx_buffer : [0, .. ::platform::drivers::primecell_uart::PL011_BUF_SZ],
+            tx_head : 0,
+            tx_count : 0,
+            handlers : [None, .. ::platform::drivers::primecell_uart::MAX_HANDLERS],
+        }
+    )
+)
+
+/// Open ports by slot, so the shared interrupt handler can find them
+static mut ports : [Option<*mut PL011>, ..MAX_PORTS] = [None, ..MAX_PORTS];
+
+impl Serial for PL011 {
 
     /// Initialize device and begin transmission. Returns true if device successfully opened.
     fn open(&mut self, r : u32) -> bool
     {
-        unsafe{
-            // Set baud rate
-            // Using tables on page 3-11 of PrimeCell ref for typical baud rates
-            let int_divisor : u16 = chip::UART_CLK / (16 * r);
-            // f_divisor is actually only a 6-bit quantity
-            let f_component : u64 = (chip::UART_CLK as u64 << 7) / ((16 * r) as u64 << 7) - int_divisor << 7 as u64;
-            let f_divisor : u8 = f_component >> 1 + f_component & 1;
+        if r == 0
+        {
+            return false;
+        }
+        // Divisor is UARTCLK / (16 * rate) with 6 fractional bits, rounded
+        // See page 3-10 of the PrimeCell ref
+        let div = (self.clock * 4 + r / 2) / r;
+        let int_divisor = div >> 6;
+        let f_divisor = div & 0x3F;
+        if int_divisor == 0 || int_divisor > 0xFFFF
+        {
+            return false;
+        }
 
-            if(int_divisor == 0 || int_divisor == (1 << 16) - 1 as u16))
-            {
-                return false;
-            }
+        let slot = match self.claim_slot() {
+            Some(i) => i,
+            None => return false
+        };
 
-            // enable PrimeCell0 IRQ [4]
-            *chip::VIC_INT_ENABLE = 1 << self.IRQ;
-            // enable RXIM interrupt (interrupt on receive)
+        unsafe {
+            // Disable, let the transmitter finish and flush the FIFOs
+            io::wh(self.base + CR, 0);
+            while io::read(self.base + FR) & FR_BUSY != 0 {}
+            io::wh(self.base + LCR_H, 0);
+
+            io::wh(self.base + IBRD, int_divisor);
+            io::wh(self.base + FBRD, f_divisor);
+            // Writing LCR_H latches the divisors
+            io::wh(self.base + LCR_H, self.line_control() | LCR_H_FEN);
+
+            io::wh(self.base + RSR_ECR, 0);
+            io::wh(self.base + ICR, INT_ALL);
+            // Interrupt with the receive FIFO 1/2 full, or on timeout for
+            // anything less, and with the transmit FIFO down to 1/8
+            io::wh(self.base + IFLS, IFLS_RX_HALF | IFLS_TX_EIGHTH);
+            // enable RXIM and RTIM interrupts
             /*
              * See
              * http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0183f/I54603.html
              */
-            io::ws(self.base + IMSC, 1 << 4);
-            // TODO: Force IRQ enable, with appropriate interrupt handler
-            /*
-            kernel::int_table.map(|t| {
-                t.enable(interrupt::IRQ, PrimeCell0_receiveInterrupt);
-            });
-            */
+            io::wh(self.base + IMSC, INT_RX | INT_RT);
+            io::wh(self.base + CR, CR_UARTEN | CR_TXE | CR_RXE);
+
+            ports[slot] = Some(self as *mut PL011);
+            interrupt::register_irq(self.IRQ, uartInterrupt);
         }
+        self.rate = r;
         self.buf_head = 0;
         self.buf_count = 0;
-        false
+        self.tx_head = 0;
+        self.tx_count = 0;
+        self.errors = LineErrors { framing : 0, parity : 0, breaks : 0, overruns : 0 };
+        true
     }
 
     fn isOpen(&self) -> bool
     {
-        self.rate == 0
+        self.rate != 0
     }
 
     /// End transmission, close device. Returns true if device is closed after operation.
     fn close(&mut self) -> bool
     {
+        if self.isOpen()
+        {
+            self.flush();
+            unsafe {
+                io::wh(self.base + IMSC, 0);
+                while io::read(self.base + FR) & FR_BUSY != 0 {}
+                io::wh(self.base + CR, 0);
+                interrupt::unregister_irq(self.IRQ);
+            }
+            self.release_slot();
+        }
         self.rate = 0;
         self.buf_head = 0;
         self.buf_count = 0;
@@ -80,7 +175,7 @@ impl Serial for PrimeCell{
     {
         self.buf_count
     }
-    
+
     /// Read up to length bytes into buffer. Return number of bytes read.
     fn readBuf(&mut self, buffer : &mut [u8], length : uint) -> uint
     {
@@ -96,99 +191,412 @@ impl Serial for PrimeCell{
     /// Read one character into buffer. Return number of bytes read.
     fn read(&mut self, c : &mut u8) -> uint
     {
-        if self.buf_count == 0 
+        let cpsr = interrupt::disable();
+        let n = if self.buf_count == 0
         {
-            return 0;
+            0
         }
         else
         {
             *c = self.buffer[self.buf_head];
-            self.buf_head += 1;
+            self.buf_head = (self.buf_head + 1) % PL011_BUF_SZ;
             self.buf_count -= 1;
-            return 1;
-        }
+            1
+        };
+        interrupt::restore(cpsr);
+        n
     }
 
-    /// Write a single byte. Return number of bytes written.
-    fn write(&self, c : u8) -> uint
+    /// Queue a single byte. It goes straight to the transmit FIFO if
+    /// nothing is queued ahead of it, otherwise into the ring buffer,
+    /// which the TX interrupt drains; when that is full, wait for room.
+    /// With interrupts masked, fall back to polling. Return number of
+    /// bytes written.
+    fn write(&mut self, c : u8) -> uint
     {
-        unsafe {
-            /*
-             * We need to include a blank asm call to prevent rustc
-             * from optimizing this part out
-             */
-            asm!("");
-            volatile_store(self.base + DR, c as u32);
+        if !self.isOpen()
+        {
+            return 0;
+        }
+        loop
+        {
+            if !interrupt::enabled()
+            {
+                // Nothing would drain the ring; keep the output in order
+                self.drain_tx_polled();
+                putc_raw(self.base, c);
+                return 1;
+            }
+            let cpsr = interrupt::disable();
+            let done = unsafe {
+                if self.tx_count == 0 && io::read(self.base + FR) & FR_TXFF == 0
+                {
+                    io::wh(self.base + DR, c as u32);
+                    true
+                }
+                else if self.tx_count < PL011_BUF_SZ
+                {
+                    self.tx_buffer[(self.tx_head + self.tx_count) % PL011_BUF_SZ] = c;
+                    self.tx_count += 1;
+                    io::ws(self.base + IMSC, INT_TX);
+                    true
+                }
+                else
+                {
+                    false
+                }
+            };
+            interrupt::restore(cpsr);
+            if done
+            {
+                return 1;
+            }
         }
-        1
     }
 
-    /// Write a buffer of bytes. Return number of bytes written.
-    fn writeBuf(&self, buffer : &[u8], length : uint) -> uint
+    /// Queue a buffer of bytes. Return number of bytes written.
+    fn writeBuf(&mut self, buffer : &[u8], length : uint) -> uint
     {
         let mut i = 0;
         while (i < length)
         {
-            self.write(buffer[i]);
+            if self.write(buffer[i]) == 0
+            {
+                break;
+            }
+            i += 1;
         }
-        return length;
+        i
     }
 
-    fn flush(&self) -> uint
+    /// Wait until the ring buffer and the transmit FIFO are empty. Return
+    /// the number of bytes that were still queued.
+    fn flush(&mut self) -> uint
     {
-        0
+        if !self.isOpen()
+        {
+            return 0;
+        }
+        let queued = self.tx_count;
+        if interrupt::enabled()
+        {
+            while self.pending() > 0 {}
+        }
+        else
+        {
+            self.drain_tx_polled();
+        }
+        unsafe {
+            while io::read(self.base + FR) & FR_BUSY != 0 {}
+        }
+        queued
     }
 
     /// Callback on new data available.
-    fn addReceiveHandler(&self, newHandler : serialReceiveHandler) -> bool
+    fn addReceiveHandler(&mut self, newHandler : serialReceiveHandler) -> Option<uint>
+    {
+        let cpsr = interrupt::disable();
+        let mut handle = None;
+        let mut i = 0;
+        while i < MAX_HANDLERS
+        {
+            match self.handlers[i]
+            {
+                None => {
+                    self.handlers[i] = Some(newHandler);
+                    handle = Some(i);
+                    break;
+                }
+                Some(_) => i += 1
+            }
+        }
+        interrupt::restore(cpsr);
+        handle
+    }
+
+    fn removeReceiveHandler(&mut self, handle : uint)
     {
-        false
+        if handle < MAX_HANDLERS
+        {
+            let cpsr = interrupt::disable();
+            self.handlers[handle] = None;
+            interrupt::restore(cpsr);
+        }
     }
 
     /// Remove all receive handlers
-    fn clearReceiveHandlers(&self)
+    fn clearReceiveHandlers(&mut self)
     {
-        ()
+        let cpsr = interrupt::disable();
+        let mut i = 0;
+        while i < MAX_HANDLERS
+        {
+            self.handlers[i] = None;
+            i += 1;
+        }
+        interrupt::restore(cpsr);
     }
 }
 
-impl PrimeCell
+impl PL011
 {
+    /// Set the line format used from the next `open`. Returns false if
+    /// the format isn't supported.
+    pub fn configure(&mut self, data_bits : u8, parity : Parity, stop_bits : u8) -> bool
+    {
+        if data_bits < 5 || data_bits > 8 || stop_bits < 1 || stop_bits > 2
+        {
+            return false;
+        }
+        self.data_bits = data_bits;
+        self.parity = parity;
+        self.stop_bits = stop_bits;
+        true
+    }
+
+    pub fn errors(&self) -> LineErrors
+    {
+        self.errors
+    }
+
+    /// LCR_H bits for the configured format, FIFOs not included
+    fn line_control(&self) -> u32
+    {
+        let mut lcr = ((self.data_bits - 5) as u32) << LCR_H_WLEN_SHIFT;
+        if self.stop_bits == 2
+        {
+            lcr |= LCR_H_STP2;
+        }
+        match self.parity
+        {
+            NoParity => (),
+            EvenParity => lcr |= LCR_H_PEN | LCR_H_EPS,
+            OddParity => lcr |= LCR_H_PEN
+        }
+        lcr
+    }
+
+    /// Buffer a received byte. When the buffer is full the oldest byte
+    /// makes room, so readers see the latest input; returns false then.
     fn receive(&mut self, c : u8) -> bool
     {
-        if(self.buf_count == PrimeCell_BUF_SZ)
+        let room = self.buf_count < PL011_BUF_SZ;
+        if !room
         {
-            false
-        }else
+            self.buf_head = (self.buf_head + 1) % PL011_BUF_SZ;
+            self.buf_count -= 1;
+        }
+        self.buffer[(self.buf_head + self.buf_count) % PL011_BUF_SZ] = c;
+        self.buf_count += 1;
+        room
+    }
+
+    /// Pass a received byte to the handlers. Returns false if there are
+    /// none.
+    fn notify(&self, c : u8) -> bool
+    {
+        let mut any = false;
+        let mut i = 0;
+        while i < MAX_HANDLERS
         {
-            self.buffer[(self.buf_head + self.buf_count) % PrimeCell_BUF_SZ] = c;
-            self.buf_count += 1;
-            true
+            match self.handlers[i]
+            {
+                Some(f) => {
+                    f(c);
+                    any = true;
+                }
+                None => ()
+            }
+            i += 1;
         }
+        any
+    }
+
+    /// Count and report the error bits that came with a received byte
+    fn line_error(&mut self, status : u32)
+    {
+        if status & DR_FE != 0 { self.errors.framing += 1; }
+        if status & DR_PE != 0 { self.errors.parity += 1; }
+        if status & DR_BE != 0 { self.errors.breaks += 1; }
+        if status & DR_OE != 0 { self.errors.overruns += 1; }
+        kwarn!("pl011 ", Hex(self.base), ": line error ", Hex(status >> 8));
+        unsafe { io::wh(self.base + RSR_ECR, 0); }
+    }
+
+    /// Drain the receive FIFO into the buffer.
+    unsafe fn drain_rx(&mut self)
+    {
+        while io::read(self.base + FR) & FR_RXFE == 0
+        {
+            let d = io::read(self.base + DR);
+            if d & DR_ERRORS != 0
+            {
+                self.line_error(d & DR_ERRORS);
+                // A break or framing error carries no data
+                if d & (DR_FE | DR_BE) != 0
+                {
+                    continue;
+                }
+            }
+            // Every byte is buffered for readers, handlers or not
+            let kept = self.receive(d as u8);
+            if !self.notify(d as u8) && !kept
+            {
+                self.errors.overruns += 1;
+            }
+        }
+    }
+
+    /// Bytes in the ring buffer, read with the TX interrupt held off
+    fn pending(&self) -> uint
+    {
+        let cpsr = interrupt::disable();
+        let n = self.tx_count;
+        interrupt::restore(cpsr);
+        n
+    }
+
+    /// Move queued bytes into the transmit FIFO until it is full. Once
+    /// the ring is empty the TX interrupt is masked again.
+    unsafe fn drain_tx(&mut self)
+    {
+        while self.tx_count > 0 && io::read(self.base + FR) & FR_TXFF == 0
+        {
+            io::wh(self.base + DR, self.tx_buffer[self.tx_head] as u32);
+            self.tx_head = (self.tx_head + 1) % PL011_BUF_SZ;
+            self.tx_count -= 1;
+        }
+        if self.tx_count == 0
+        {
+            io::wh(self.base + IMSC, io::read(self.base + IMSC) & !INT_TX);
+        }
+    }
+
+    /// Send everything queued by polling, for when interrupts are masked
+    fn drain_tx_polled(&mut self)
+    {
+        while self.tx_count > 0
+        {
+            putc_raw(self.base, self.tx_buffer[self.tx_head]);
+            self.tx_head = (self.tx_head + 1) % PL011_BUF_SZ;
+            self.tx_count -= 1;
+        }
+        unsafe {
+            io::wh(self.base + IMSC, io::read(self.base + IMSC) & !INT_TX);
+        }
+    }
+
+    fn claim_slot(&mut self) -> Option<uint>
+    {
+        unsafe {
+            let me = self as *mut PL011;
+            let mut free = None;
+            let mut i = 0;
+            while i < MAX_PORTS
+            {
+                match ports[i]
+                {
+                    Some(p) if p == me => return Some(i),
+                    None if free.is_none() => free = Some(i),
+                    _ => ()
+                }
+                i += 1;
+            }
+            free
+        }
+    }
+
+    fn release_slot(&mut self)
+    {
+        unsafe {
+            let me = self as *mut PL011;
+            let mut i = 0;
+            while i < MAX_PORTS
+            {
+                match ports[i]
+                {
+                    Some(p) if p == me => ports[i] = None,
+                    _ => ()
+                }
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Polled write of one byte to the PL011 at `base`. Works with
+/// interrupts masked, for consoles and exception reports.
+pub fn putc_raw(base : u32, c : u8)
+{
+    unsafe {
+        while io::read(base + FR) & FR_TXFF != 0 {}
+        io::wh(base + DR, c as u32);
+    }
+}
+
+/// Shared IRQ handler for every open port
+unsafe fn uartInterrupt(line : u8)
+{
+    let mut i = 0;
+    while i < MAX_PORTS
+    {
+        match ports[i]
+        {
+            Some(p) if (*p).IRQ == line => {
+                let status = io::read((*p).base + MIS);
+                if status & (INT_RX | INT_RT) != 0
+                {
+                    (*p).drain_rx();
+                }
+                if status & INT_TX != 0
+                {
+                    (*p).drain_tx();
+                }
+                io::wh((*p).base + ICR, status & (INT_RX | INT_RT | INT_TX | INT_ERRORS));
+            }
+            _ => ()
+        }
+        i += 1;
     }
 }
 
 static DR       : u32 = 0x000; // Data register, UARTDR on page 3-5
 static RSR_ECR  : u32 = 0x004; // Receive status register/error clear register, UARTRSR/UARTECR on page 3-6
-static FR       : u32 = 0x014; // Flag register, UARTFR on page 3-8
-static ILPR     : u32 = 0x01C; // IrDA low-power counter register, UARTILPR on page 3-9
+static FR       : u32 = 0x018; // Flag register, UARTFR on page 3-8
 static IBRD     : u32 = 0x024; // Integer baud rate register, UARTIBRD on page 3-10
 static FBRD     : u32 = 0x028; // Fractional baud rate register, UARTFBRD on page 3-10
 static LCR_H    : u32 = 0x02C; // Line control register, UARTLCR_H on page 3-12
 static CR       : u32 = 0x030; // Control register, UARTCR on page 3-15
 static IFLS     : u32 = 0x034; // Interrupt FIFO level select register, UARTIFLS on page 3-17
 static IMSC     : u32 = 0x038; // Interrupt mask set/clear register, UARTIMSC on page 3-17
-static RIS      : u32 = 0x03C; // Raw interrupt status register, UARTRIS on page 3-19
 static MIS      : u32 = 0x040; // Masked interrupt status register, UARTMIS on page 3-20
 static ICR      : u32 = 0x044; // Interrupt cler register, UARTICR on page 3-21
-static DMACR    : u32 = 0x048; // DMA control register, UARTDMACR on page 3-22
-static PeriphID0: u32 = 0xFE0; // UARTPeriphID0 register on page 3-23
-static PeriphID1: u32 = 0xFE4; // UARTPeriphID1 register on page 3-24
-static PeriphID2: u32 = 0xFE8; // UARTPeriphID2 register on page 3-24
-static PeriphID3: u32 = 0xFEC; // UARTPeriphID3 register on page 3-24
-static PCellID0 : u32 = 0xFF0; // UARTPCellID0 register on page 3-25
-static PCellID1 : u32 = 0xFF4; // UARTPCellID1 register on page 3-26
-static PCellID2 : u32 = 0xFF8; // UARTPCellID2 register on page 3-26
-static PCellID3 : u32 = 0xFFC; // UARTPCellID3 register on page 3-26
 
+static DR_FE    : u32 = 1 << 8;  // Framing error
+static DR_PE    : u32 = 1 << 9;  // Parity error
+static DR_BE    : u32 = 1 << 10; // Break error
+static DR_OE    : u32 = 1 << 11; // Overrun error
+static DR_ERRORS: u32 = 0xF00;
+
+static FR_BUSY  : u32 = 1 << 3;
+static FR_RXFE  : u32 = 1 << 4;
+static FR_TXFF  : u32 = 1 << 5;
+
+static LCR_H_PEN        : u32 = 1 << 1;
+static LCR_H_EPS        : u32 = 1 << 2;
+static LCR_H_STP2       : u32 = 1 << 3;
+static LCR_H_FEN        : u32 = 1 << 4;
+static LCR_H_WLEN_SHIFT : u32 = 5;
+
+static CR_UARTEN : u32 = 1 << 0;
+static CR_TXE    : u32 = 1 << 8;
+static CR_RXE    : u32 = 1 << 9;
+
+static IFLS_RX_HALF   : u32 = 2 << 3;
+static IFLS_TX_EIGHTH : u32 = 0;
 
+static INT_RX     : u32 = 1 << 4;
+static INT_TX     : u32 = 1 << 5;
+static INT_RT     : u32 = 1 << 6;
+static INT_ERRORS : u32 = 0xF << 7; // FE, PE, BE, OE
+static INT_ALL    : u32 = 0x7FF;
@@ -0,0 +1,66 @@
+/* kernel::board */
+/* What the kernel needs from the machine it runs on */
+
+use core::option::{Option, Some, None};
+
+use kernel::screen::TerminalCanvas;
+use kernel::serial;
+use kernel::serial::Serial;
+
+/// One supported machine. Each chip module under `platform::drivers`
+/// provides one and `platform::drivers::board()` returns the one built in;
+/// the rest of the kernel reaches the hardware only through it.
+pub trait Board {
+    /// Short name for boot messages
+    fn name(&self) -> &'static str;
+    /// Register a driver for each device the board may have with
+    /// `kernel::device`, which probes them next.
+    unsafe fn init(&self);
+
+    /// Peripheral window to identity-map, as (base, size)
+    fn io_window(&self) -> (u32, u32);
+    /// RAM to assume when the bootloader doesn't describe it, as (base,
+    /// size). None if there is no safe guess, and the kernel halts.
+    fn default_ram(&self) -> Option<(uint, uint)>;
+
+    fn serial_count(&self) -> uint;
+    /// Serial port `i`
+    fn serial(&self, i : uint) -> Option<&'static mut Serial>;
+    /// Device name of serial port `i`, as under /dev
+    fn serial_name(&self, i : uint) -> Option<&'static str>;
+    /// The port kernel messages and user programs' stdio go to: the one
+    /// `console=` names, the same the shell takes
+    fn console(&self) -> &'static mut Serial
+    {
+        match self.serial(serial::console_index()) {
+            Some(port) => port,
+            None => match self.serial(0) {
+                Some(port) => port,
+                None => kpanic!("board has no serial port")
+            }
+        }
+    }
+    /// Polled write to the console port that works with interrupts masked,
+    /// for reports from exception handlers.
+    fn putc_raw(&self, c : u8);
+
+    /// The framebuffer console, once the screen has a mode set
+    fn display(&self) -> Option<&'static mut TerminalCanvas>;
+
+    /// Interrupt lines the controller has
+    fn nr_irqs(&self) -> uint;
+    unsafe fn irq_enable(&self, line : u8);
+    unsafe fn irq_disable(&self, line : u8);
+    /// Next line with an active, unmasked request
+    unsafe fn irq_next_pending(&self) -> Option<u8>;
+    /// Signal end of interrupt once every pending line has been serviced
+    unsafe fn irq_ack(&self);
+
+    /// Interrupt `hz` times a second, calling `handler` on every tick.
+    fn timer_start(&self, hz : u32, handler : fn()) -> bool;
+    fn timer_stop(&self);
+    /// Microseconds since the last tick the handler counted, including a
+    /// tick that is pending, so adding it to the tick count never goes
+    /// backwards.
+    fn timer_elapsed_us(&self) -> u32;
+}
@@ -0,0 +1,226 @@
+/* kernel::boot */
+/* What the boot loader tells us: the ATAG list or flattened device tree
+ * whose address arrives in r2, holding the RAM layout, the kernel command
+ * line and the initrd location. */
+
+use core::option::{Option, Some, None};
+use core::container::Container;
+use core::mem::{volatile_load, transmute};
+use core::str::as_bytes;
+
+/// r0-r2 as they were at `start` (see loader.s)
+extern {
+    static boot_regs: [u32, ..3];
+}
+
+/// Where QEMU and most loaders put the ATAG list
+static DEFAULT_ATAGS: uint = 0x100;
+
+static ATAG_NONE: u32 = 0;
+static ATAG_CORE: u32 = 0x54410001;
+static ATAG_MEM: u32 = 0x54410002;
+static ATAG_CMDLINE: u32 = 0x54410009;
+static ATAG_INITRD2: u32 = 0x54420005;
+
+static FDT_MAGIC: u32 = 0xd00dfeed;
+static FDT_BEGIN_NODE: u32 = 1;
+static FDT_END_NODE: u32 = 2;
+static FDT_PROP: u32 = 3;
+static FDT_NOP: u32 = 4;
+static FDT_END: u32 = 9;
+
+pub static CMDLINE_MAX: uint = 256;
+
+pub struct BootInfo {
+    /// Machine type from r1
+    machine: u32,
+    mem_start: uint,
+    mem_size: uint,
+    initrd_start: uint,
+    initrd_size: uint,
+    cmdline: [u8, ..CMDLINE_MAX],
+    cmdline_len: uint
+}
+
+pub static mut info: BootInfo = BootInfo {
+    machine: 0,
+    mem_start: 0,
+    mem_size: 0,
+    initrd_start: 0,
+    initrd_size: 0,
+    cmdline: [0, ..CMDLINE_MAX],
+    cmdline_len: 0
+};
+
+/// Read the boot loader's tables. Must run before anything allocates
+/// memory, since they may sit in what becomes the heap or frame region.
+pub fn init() {
+    unsafe {
+        info.machine = boot_regs[1];
+        let tags = boot_regs[2] as uint;
+        if tags != 0 && be32(tags) == FDT_MAGIC {
+            parse_fdt(tags);
+        } else if tags != 0 && word(tags + 4) == ATAG_CORE {
+            parse_atags(tags);
+        } else if word(DEFAULT_ATAGS + 4) == ATAG_CORE {
+            parse_atags(DEFAULT_ATAGS);
+        }
+    }
+}
+
+/// End of RAM, if the loader told us
+pub fn mem_end() -> Option<uint> {
+    unsafe {
+        if info.mem_size == 0 { None } else { Some(info.mem_start + info.mem_size) }
+    }
+}
+
+/// Start and length of the initrd image
+pub fn initrd() -> Option<(uint, uint)> {
+    unsafe {
+        if info.initrd_size == 0 { None } else { Some((info.initrd_start, info.initrd_size)) }
+    }
+}
+
+/// The kernel command line, empty if there was none
+pub fn cmdline() -> &'static [u8] {
+    unsafe { bytes(&info.cmdline[0] as *u8, info.cmdline_len) }
+}
+
+unsafe fn parse_atags(mut tag: uint) {
+    loop {
+        let size = word(tag) as uint;
+        let kind = word(tag + 4);
+        if size < 2 || kind == ATAG_NONE {
+            break;
+        }
+        match kind {
+            ATAG_MEM => {
+                // Keep the first bank; the others aren't contiguous with it
+                if info.mem_size == 0 {
+                    info.mem_size = word(tag + 8) as uint;
+                    info.mem_start = word(tag + 12) as uint;
+                }
+            }
+            ATAG_INITRD2 => {
+                info.initrd_start = word(tag + 8) as uint;
+                info.initrd_size = word(tag + 12) as uint;
+            }
+            ATAG_CMDLINE => set_cmdline(tag + 8, (size - 2) * 4),
+            _ => ()
+        }
+        tag += size * 4;
+    }
+}
+
+/// Walk the structure block for /memory's "reg" and /chosen's
+/// "bootargs", "linux,initrd-start" and "linux,initrd-end". Assumes one
+/// address and one size cell, which is what 32-bit boards use.
+unsafe fn parse_fdt(blob: uint) {
+    let structs = blob + be32(blob + 8) as uint;
+    let strings = blob + be32(blob + 12) as uint;
+    let mut p = structs;
+    let mut depth = 0;
+    // Which depth-1 node we're inside
+    let mut in_memory = false;
+    let mut in_chosen = false;
+    let mut initrd_end = 0;
+    loop {
+        let token = be32(p);
+        p += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                depth += 1;
+                if depth == 2 {
+                    in_memory = prefix(p, "memory");
+                    in_chosen = prefix(p, "chosen");
+                }
+                while volatile_load(p as *u8) != 0 {
+                    p += 1;
+                }
+                p = align4(p + 1);
+            }
+            FDT_END_NODE => {
+                if depth == 2 {
+                    in_memory = false;
+                    in_chosen = false;
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = be32(p) as uint;
+                let name = strings + be32(p + 4) as uint;
+                let value = p + 8;
+                if in_memory && equals(name, "reg") && len >= 8 && info.mem_size == 0 {
+                    info.mem_start = be32(value) as uint;
+                    info.mem_size = be32(value + 4) as uint;
+                } else if in_chosen && equals(name, "bootargs") && len > 0 {
+                    set_cmdline(value, len);
+                } else if in_chosen && equals(name, "linux,initrd-start") && len >= 4 {
+                    // 32 or 64 bits wide; the low word comes last
+                    info.initrd_start = be32(value + len - 4) as uint;
+                } else if in_chosen && equals(name, "linux,initrd-end") && len >= 4 {
+                    initrd_end = be32(value + len - 4) as uint;
+                }
+                p = align4(value + len);
+            }
+            FDT_NOP => (),
+            FDT_END => break,
+            _ => break // malformed
+        }
+    }
+    if initrd_end > info.initrd_start {
+        info.initrd_size = initrd_end - info.initrd_start;
+    }
+}
+
+/// Copy a NUL-terminated (or `max`-byte) string into `info.cmdline`.
+unsafe fn set_cmdline(src: uint, max: uint) {
+    let mut i = 0;
+    while i < max && i < CMDLINE_MAX {
+        let c = volatile_load((src + i) as *u8);
+        if c == 0 {
+            break;
+        }
+        info.cmdline[i] = c;
+        i += 1;
+    }
+    info.cmdline_len = i;
+}
+
+/// Does the NUL-terminated string at `p` equal `s`?
+unsafe fn equals(p: uint, s: &str) -> bool {
+    prefix(p, s) && volatile_load((p + s.len()) as *u8) == 0
+}
+
+/// Does the node name at `p` start with `s`, up to its unit address?
+unsafe fn prefix(p: uint, s: &str) -> bool {
+    let s = as_bytes(s);
+    let mut i = 0;
+    while i < s.len() {
+        if volatile_load((p + i) as *u8) != s[i] {
+            return false;
+        }
+        i += 1;
+    }
+    let next = volatile_load((p + i) as *u8);
+    next == 0 || next == '@' as u8
+}
+
+unsafe fn word(addr: uint) -> u32 {
+    volatile_load(addr as *u32)
+}
+
+/// Device trees are big-endian
+unsafe fn be32(addr: uint) -> u32 {
+    let x = word(addr);
+    (x >> 24) | ((x >> 8) & 0xff00) | ((x << 8) & 0xff0000) | (x << 24)
+}
+
+fn align4(x: uint) -> uint {
+    (x + 3) & !3
+}
+
+unsafe fn bytes<'a>(p: *u8, len: uint) -> &'a [u8] {
+    transmute((p, len))
+}
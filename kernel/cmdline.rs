@@ -0,0 +1,95 @@
+/* kernel::cmdline */
+/* Boot options from the kernel command line (QEMU -append): space
+ * separated `key=value` pairs and bare flags, e.g.
+ *
+ *     console=uart0 baud=115200 video=800x600 loglevel=3 init=/initrd/rc
+ */
+
+use core::option::{Option, Some, None};
+use core::container::Container;
+use core::str::as_bytes;
+
+use kernel::boot;
+use kernel::fs::{eq, sub};
+
+/// The value of `key`, if given. A bare flag has an empty value.
+pub fn get(key: &str) -> Option<&'static [u8]> {
+    let line = boot::cmdline();
+    let key = as_bytes(key);
+    let mut i = 0;
+    while i < line.len() {
+        while i < line.len() && line[i] == ' ' as u8 {
+            i += 1;
+        }
+        let start = i;
+        while i < line.len() && line[i] != ' ' as u8 {
+            i += 1;
+        }
+        let opt = sub(line, start, i);
+        if opt.len() >= key.len() && eq(sub(opt, 0, key.len()), key) {
+            if opt.len() == key.len() {
+                return Some(sub(opt, 0, 0));
+            }
+            if opt[key.len()] == '=' as u8 {
+                return Some(sub(opt, key.len() + 1, opt.len()));
+            }
+        }
+    }
+    None
+}
+
+/// Was `key` given at all, with or without a value?
+pub fn has(key: &str) -> bool {
+    get(key).is_some()
+}
+
+/// Is `key` set to exactly `value`?
+pub fn is(key: &str, value: &str) -> bool {
+    match get(key) {
+        Some(v) => eq(v, as_bytes(value)),
+        None => false
+    }
+}
+
+/// The value of `key` as a decimal number
+pub fn get_uint(key: &str) -> Option<uint> {
+    match get(key) {
+        Some(v) => parse_uint(v),
+        None => None
+    }
+}
+
+/// Two numbers separated by `sep`, as in `video=800x600`
+pub fn get_pair(key: &str, sep: char) -> Option<(uint, uint)> {
+    let v = match get(key) {
+        Some(v) => v,
+        None => return None
+    };
+    let mut i = 0;
+    while i < v.len() && v[i] != sep as u8 {
+        i += 1;
+    }
+    if i == v.len() {
+        return None;
+    }
+    match (parse_uint(sub(v, 0, i)), parse_uint(sub(v, i + 1, v.len()))) {
+        (Some(a), Some(b)) => Some((a, b)),
+        _ => None
+    }
+}
+
+fn parse_uint(s: &[u8]) -> Option<uint> {
+    if s.len() == 0 {
+        return None;
+    }
+    let mut n = 0;
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] < '0' as u8 || s[i] > '9' as u8 {
+            return None;
+        }
+        n = n * 10 + (s[i] - '0' as u8) as uint;
+        i += 1;
+    }
+    Some(n)
+}
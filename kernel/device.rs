@@ -0,0 +1,140 @@
+/* kernel::device */
+/* Driver registry. Boards register a driver for each device they may
+ * have; `probe_all` asks each whether its hardware is there and brings up
+ * the ones that are. */
+
+use core::option::{Option, Some, None};
+
+use kernel::log::Hex;
+
+static MAX_DRIVERS: uint = 16;
+
+/// Capability bits: what a bound device offers the rest of the kernel
+pub static SERIAL: uint = 1 << 0;
+pub static CANVAS: uint = 1 << 1;
+pub static BLOCK: uint = 1 << 2;
+pub static INPUT: uint = 1 << 3;
+
+pub struct Driver {
+    /// Device name, also used under /dev where the driver publishes one
+    name: &'static str,
+    /// "vendor,part" of the hardware handled, e.g. "arm,pl011"
+    compatible: &'static str,
+    /// Register base, or 0 if the device has none of its own
+    base: u32,
+    /// Which of several devices sharing these hooks this is
+    id: uint,
+    caps: uint,
+    /// Is the hardware present? Must not change its state.
+    probe: fn(&Driver) -> bool,
+    /// Bring the device up and publish it. False if that failed.
+    init: fn(&Driver) -> bool,
+    shutdown: Option<fn(&Driver)>
+}
+
+pub enum State {
+    /// Registered, not probed yet
+    Pending,
+    /// Probe found nothing
+    Absent,
+    /// Probe succeeded but init did not
+    Failed,
+    Bound
+}
+
+struct Entry {
+    driver: &'static Driver,
+    state: State
+}
+
+static mut drivers: [Option<Entry>, ..MAX_DRIVERS] = [None, ..MAX_DRIVERS];
+
+/// `probe` for devices that are always there or can't be detected
+pub fn always(_: &Driver) -> bool {
+    true
+}
+
+/// Add `d` to the registry. Probed by the next `probe_all`.
+pub fn register(d: &'static Driver) -> bool {
+    unsafe {
+        let mut i = 0;
+        while i < MAX_DRIVERS {
+            match drivers[i] {
+                Some(_) => i += 1,
+                None => {
+                    drivers[i] = Some(Entry { driver: d, state: Pending });
+                    return true;
+                }
+            }
+        }
+    }
+    kwarn!("device: registry full, dropping ", d.name);
+    false
+}
+
+/// Probe every pending driver in registration order and initialize the
+/// ones whose hardware answers. Returns how many were bound.
+pub fn probe_all() -> uint {
+    let mut bound = 0;
+    unsafe {
+        let mut i = 0;
+        while i < MAX_DRIVERS {
+            match drivers[i] {
+                Some(ref mut e) => match e.state {
+                    Pending => {
+                        let d = e.driver;
+                        e.state = if !(d.probe)(d) {
+                            Absent
+                        } else if (d.init)(d) {
+                            kinfo!("device: ", d.name, " (", d.compatible, " at ", Hex(d.base), ")");
+                            bound += 1;
+                            Bound
+                        } else {
+                            kwarn!("device: ", d.name, " found but failed to start");
+                            Failed
+                        };
+                    }
+                    _ => ()
+                },
+                None => ()
+            }
+            i += 1;
+        }
+    }
+    bound
+}
+
+/// Shut bound devices down, last bound first. They go back to pending, so
+/// the next `probe_all` would bring them up again.
+pub fn shutdown_all() {
+    unsafe {
+        let mut i = MAX_DRIVERS;
+        while i > 0 {
+            i -= 1;
+            match drivers[i] {
+                Some(ref mut e) => match (e.state, e.driver.shutdown) {
+                    (Bound, Some(f)) => {
+                        f(e.driver);
+                        e.state = Pending;
+                    }
+                    (Bound, None) => e.state = Pending,
+                    _ => ()
+                },
+                None => ()
+            }
+        }
+    }
+}
+
+/// Registry entry `i` and how it fared, for listing
+pub fn get(i: uint) -> Option<(&'static Driver, State)> {
+    if i >= MAX_DRIVERS {
+        return None;
+    }
+    unsafe {
+        match drivers[i] {
+            Some(e) => Some((e.driver, e.state)),
+            None => None
+        }
+    }
+}
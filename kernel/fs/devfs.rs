@@ -0,0 +1,174 @@
+/* kernel::fs::devfs */
+/* Device filesystem: a flat directory of registered devices. Inode 0 is
+ * the directory, inode i + 1 is device i. */
+
+use core::option::{Option, Some, None};
+use core::container::Container;
+use core::str::as_bytes;
+
+use kernel::fs::{FileSystem, Directory, Device, InodeId, Stat, DirEntry, NAME_MAX, eq, sub};
+use kernel::serial::{Serial, console_baud};
+use kernel::screen::TerminalCanvas;
+
+static MAX_DEVICES: uint = 8;
+
+pub enum Dev {
+    SerialDev(&'static mut Serial),
+    CanvasDev(&'static mut TerminalCanvas)
+}
+
+struct Entry {
+    name: [u8, ..NAME_MAX],
+    name_len: uint,
+    dev: Dev
+}
+
+pub struct DevFs;
+
+static mut devices: [Option<Entry>, ..MAX_DEVICES] = [None, None, None, None, None, None, None, None];
+
+/// Make `dev` available as /dev/<name>.
+pub fn register(name: &str, dev: Dev) -> bool {
+    let name = as_bytes(name);
+    if name.len() > NAME_MAX {
+        return false;
+    }
+    unsafe {
+        let mut i = 0;
+        while i < MAX_DEVICES {
+            match devices[i] {
+                Some(_) => i += 1,
+                None => {
+                    let mut e = Entry { name: [0, ..NAME_MAX], name_len: name.len(), dev: dev };
+                    let mut j = 0;
+                    while j < name.len() {
+                        e.name[j] = name[j];
+                        j += 1;
+                    }
+                    devices[i] = Some(e);
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Remove /dev/<name>, e.g. when its device shuts down.
+pub fn unregister(name: &str) -> bool {
+    let name = as_bytes(name);
+    unsafe {
+        let mut i = 0;
+        while i < MAX_DEVICES {
+            let found = match devices[i] {
+                Some(ref e) => eq(sub(&e.name, 0, e.name_len), name),
+                None => false
+            };
+            if found {
+                devices[i] = None;
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+fn device(ino: InodeId) -> Option<&'static mut Entry> {
+    if ino == 0 || ino > MAX_DEVICES {
+        return None;
+    }
+    unsafe {
+        match devices[ino - 1] {
+            Some(ref mut e) => Some(e),
+            None => None
+        }
+    }
+}
+
+impl FileSystem for DevFs {
+    fn root(&mut self) -> InodeId {
+        0
+    }
+
+    fn lookup(&mut self, dir: InodeId, name: &[u8]) -> Option<InodeId> {
+        if dir != 0 {
+            return None;
+        }
+        let mut i = 1;
+        while i <= MAX_DEVICES {
+            match device(i) {
+                Some(e) if eq(sub(&e.name, 0, e.name_len), name) => return Some(i),
+                _ => i += 1
+            }
+        }
+        None
+    }
+
+    fn stat(&mut self, ino: InodeId) -> Option<Stat> {
+        if ino == 0 {
+            return Some(Stat { kind: Directory, size: 0 });
+        }
+        device(ino).map(|_| Stat { kind: Device, size: 0 })
+    }
+
+    fn readdir(&mut self, dir: InodeId, index: uint) -> Option<DirEntry> {
+        if dir != 0 {
+            return None;
+        }
+        let mut seen = 0;
+        let mut i = 1;
+        while i <= MAX_DEVICES {
+            match device(i) {
+                Some(e) => {
+                    if seen == index {
+                        return Some(DirEntry::new(sub(&e.name, 0, e.name_len), Device));
+                    }
+                    seen += 1;
+                }
+                None => ()
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Devices are streams, so offsets are ignored.
+    fn read(&mut self, ino: InodeId, _offset: uint, buf: &mut [u8]) -> uint {
+        match device(ino) {
+            Some(e) => match e.dev {
+                SerialDev(ref mut s) => {
+                    if !s.isOpen() && !s.open(console_baud()) {
+                        return 0;
+                    }
+                    let len = buf.len();
+                    s.readBuf(buf, len)
+                }
+                CanvasDev(_) => 0
+            },
+            None => 0
+        }
+    }
+
+    fn write(&mut self, ino: InodeId, _offset: uint, data: &[u8]) -> uint {
+        match device(ino) {
+            Some(e) => match e.dev {
+                SerialDev(ref mut s) => {
+                    if !s.isOpen() && !s.open(console_baud()) {
+                        return 0;
+                    }
+                    s.writeBuf(data, data.len())
+                }
+                CanvasDev(ref mut scr) => {
+                    let mut i = 0;
+                    while i < data.len() {
+                        unsafe { scr.putChar(data[i]); }
+                        i += 1;
+                    }
+                    data.len()
+                }
+            },
+            None => 0
+        }
+    }
+}
@@ -0,0 +1,363 @@
+/* kernel::fs::initrd */
+/* Read-only filesystem over a cpio "newc" archive loaded by the boot
+ * loader (QEMU -initrd). Inode 0 is the root, inode i + 1 is archive
+ * member i. Directories only exist if the archive has an entry for them,
+ * which `find . | cpio -o -H newc` always produces. */
+
+use core::option::{Option, Some, None};
+use core::container::Container;
+use core::mem::{size_of, transmute};
+use core::ptr::copy_memory;
+use core::str::as_bytes;
+
+use kernel;
+use kernel::boot;
+use kernel::memory::Allocator;
+use kernel::memory::physical;
+use kernel::fs;
+use kernel::log::Hex;
+use kernel::fs::{FileSystem, Regular, Directory, InodeId, Stat, DirEntry, eq, sub};
+
+/// Where QEMU loads the initrd for a raw image
+static QEMU_INITRD_BASE: uint = 0xd00000;
+
+static HEADER_LEN: uint = 110;
+static MAGIC: &'static str = "070701";
+static TRAILER: &'static str = "TRAILER!!!";
+
+// File type bits of the mode (octal 0170000, 0040000, 0100000)
+static S_IFMT: uint = 0xF000;
+static S_IFDIR: uint = 0x4000;
+static S_IFREG: uint = 0x8000;
+
+struct Entry {
+    /// Path within the archive, without a leading "./" or "/"
+    path: uint,
+    path_len: uint,
+    data: uint,
+    size: uint,
+    dir: bool
+}
+
+pub struct InitRd {
+    base: *u8,
+    len: uint,
+    entries: *mut Entry,
+    count: uint
+}
+
+static mut image: InitRd = InitRd { base: 0 as *u8, len: 0, entries: 0 as *mut Entry, count: 0 };
+
+/// Copy the archive out of the way of the frame allocator and mount it at
+/// /initrd. Must run before anything else allocates frames, since the
+/// loader drops the image inside the frame region.
+pub fn init() {
+    unsafe {
+        let (start, len) = match locate() {
+            Some(r) => r,
+            None => return
+        };
+        let frames = (len + (1 << physical::frames.el_size) - 1) >> physical::frames.el_size;
+        let (copy, got) = physical::frames.alloc(frames);
+        if got == 0 {
+            kwarn!("initrd: no room for ", len, " bytes");
+            return;
+        }
+        // The copy may overlap the original; copy_memory handles that
+        copy_memory(copy, start as *u8, len);
+        image = match InitRd::new(copy as *u8, len) {
+            Some(rd) => rd,
+            None => {
+                kwarn!("initrd: not a cpio newc archive at ", Hex(start as u32));
+                physical::free_frames(copy);
+                return;
+            }
+        };
+        fs::mount(as_bytes("/initrd"), &mut image as &'static mut FileSystem);
+        kinfo!("initrd: ", len, " bytes, ", image.count, " entries at /initrd");
+    }
+}
+
+/// Start and length of the image: from the boot loader's tables, or
+/// wherever QEMU puts it by default if we weren't told.
+fn locate() -> Option<(uint, uint)> {
+    match boot::initrd() {
+        Some(r) => return Some(r),
+        None => ()
+    }
+    unsafe {
+        let base = QEMU_INITRD_BASE as *u8;
+        if eq(bytes(base, 6), as_bytes(MAGIC)) {
+            // No length to go on: walk the headers to the trailer
+            match archive_len(base) {
+                Some(len) => Some((QEMU_INITRD_BASE, len)),
+                None => None
+            }
+        } else {
+            None
+        }
+    }
+}
+
+/// Length of the archive at `base` up to the end of its trailer
+unsafe fn archive_len(base: *u8) -> Option<uint> {
+    let mut off = 0;
+    loop {
+        // Nothing to bound the walk by but the address space; keep room
+        // for the header and two rounds of padding
+        let left = !(base as uint) - off;
+        if left < HEADER_LEN + 6 {
+            return None;
+        }
+        let left = left - HEADER_LEN - 6;
+        let hdr = bytes((base as uint + off) as *u8, HEADER_LEN);
+        if !eq(sub(hdr, 0, 6), as_bytes(MAGIC)) {
+            return None;
+        }
+        let name_len = field(hdr, 11);
+        let size = field(hdr, 6);
+        if name_len == 0 || name_len > left || size > left - name_len {
+            return None;
+        }
+        let name = bytes((base as uint + off + HEADER_LEN) as *u8, name_len - 1);
+        off = align4(align4(off + HEADER_LEN + name_len) + size);
+        if eq(name, as_bytes(TRAILER)) {
+            return Some(off);
+        }
+    }
+}
+
+impl InitRd {
+    /// Index the archive at [base, base + len).
+    unsafe fn new(base: *u8, len: uint) -> Option<InitRd> {
+        let mut rd = InitRd { base: base, len: len, entries: 0 as *mut Entry, count: 0 };
+        let count = match rd.scan() {
+            Some(n) => n,
+            None => return None
+        };
+        if count > 0 {
+            rd.entries = kernel::zero_alloc(count * size_of::<Entry>()) as *mut Entry;
+            rd.scan();
+        }
+        rd.count = count;
+        Some(rd)
+    }
+
+    /// Walk the headers, recording entries once `entries` is allocated.
+    /// Returns the number of entries, or None if the archive is malformed.
+    unsafe fn scan(&mut self) -> Option<uint> {
+        let mut off = 0;
+        let mut n = 0;
+        loop {
+            if off + HEADER_LEN > self.len {
+                return None;
+            }
+            let hdr = bytes((self.base as uint + off) as *u8, HEADER_LEN);
+            if !eq(sub(hdr, 0, 6), as_bytes(MAGIC)) {
+                return None;
+            }
+            let mode = field(hdr, 1);
+            let size = field(hdr, 6);
+            let name_len = field(hdr, 11);
+            if name_len == 0 {
+                return None;
+            }
+            // The header fields are untrusted: compare against what's
+            // left rather than adding them to an offset that could wrap
+            let name = off + HEADER_LEN;
+            if name_len > self.len - name {
+                return None;
+            }
+            let data = align4(name + name_len);
+            if data > self.len || size > self.len - data {
+                return None;
+            }
+            let (path, path_len) = self.strip(name, name_len - 1);
+            if eq(bytes((self.base as uint + path) as *u8, path_len), as_bytes(TRAILER)) {
+                return Some(n);
+            }
+            let kind = mode & S_IFMT;
+            if path_len > 0 && (kind == S_IFDIR || kind == S_IFREG) {
+                if self.entries as uint != 0 {
+                    *offset(self.entries, n) = Entry {
+                        path: path,
+                        path_len: path_len,
+                        data: data,
+                        size: size,
+                        dir: kind == S_IFDIR
+                    };
+                }
+                n += 1;
+            }
+            off = align4(data + size);
+        }
+    }
+
+    /// Drop leading "./" and "/" from the name at `off`; "." becomes empty.
+    fn strip(&self, mut off: uint, mut len: uint) -> (uint, uint) {
+        loop {
+            let p = self.at(off, len);
+            if len >= 2 && p[0] == '.' as u8 && p[1] == '/' as u8 {
+                off += 2;
+                len -= 2;
+            } else if len >= 1 && p[0] == '/' as u8 {
+                off += 1;
+                len -= 1;
+            } else if len == 1 && p[0] == '.' as u8 {
+                return (off, 0);
+            } else {
+                return (off, len);
+            }
+        }
+    }
+
+    fn at<'a>(&'a self, off: uint, len: uint) -> &'a [u8] {
+        unsafe { bytes((self.base as uint + off) as *u8, len) }
+    }
+
+    fn entry<'a>(&'a self, ino: InodeId) -> Option<&'a Entry> {
+        if ino == 0 || ino > self.count {
+            None
+        } else {
+            unsafe { Some(&*offset(self.entries, ino - 1)) }
+        }
+    }
+
+    fn path<'a>(&'a self, ino: InodeId) -> &'a [u8] {
+        match self.entry(ino) {
+            Some(e) => self.at(e.path, e.path_len),
+            None => &[]
+        }
+    }
+
+    /// Name of `ino` if it sits directly inside directory `dir`
+    fn child_name<'a>(&'a self, dir: InodeId, ino: InodeId) -> Option<&'a [u8]> {
+        let parent = self.path(dir);
+        let path = self.path(ino);
+        let start = if parent.len() == 0 {
+            0
+        } else if path.len() > parent.len() + 1 && eq(sub(path, 0, parent.len()), parent)
+                  && path[parent.len()] == '/' as u8 {
+            parent.len() + 1
+        } else {
+            return None;
+        };
+        let mut i = start;
+        while i < path.len() {
+            if path[i] == '/' as u8 {
+                return None;
+            }
+            i += 1;
+        }
+        Some(sub(path, start, path.len()))
+    }
+
+    fn is_dir(&self, ino: InodeId) -> bool {
+        match self.entry(ino) {
+            Some(e) => e.dir,
+            None => ino == 0
+        }
+    }
+}
+
+impl FileSystem for InitRd {
+    fn root(&mut self) -> InodeId {
+        0
+    }
+
+    fn lookup(&mut self, dir: InodeId, name: &[u8]) -> Option<InodeId> {
+        if !self.is_dir(dir) {
+            return None;
+        }
+        let mut ino = 1;
+        while ino <= self.count {
+            match self.child_name(dir, ino) {
+                Some(n) if eq(n, name) => return Some(ino),
+                _ => ino += 1
+            }
+        }
+        None
+    }
+
+    fn stat(&mut self, ino: InodeId) -> Option<Stat> {
+        if ino == 0 {
+            return Some(Stat { kind: Directory, size: 0 });
+        }
+        self.entry(ino).map(|e| Stat { kind: if e.dir { Directory } else { Regular }, size: e.size })
+    }
+
+    fn readdir(&mut self, dir: InodeId, index: uint) -> Option<DirEntry> {
+        if !self.is_dir(dir) {
+            return None;
+        }
+        let mut seen = 0;
+        let mut ino = 1;
+        while ino <= self.count {
+            match self.child_name(dir, ino) {
+                Some(name) => {
+                    if seen == index {
+                        return Some(DirEntry::new(name, if self.is_dir(ino) { Directory } else { Regular }));
+                    }
+                    seen += 1;
+                }
+                None => ()
+            }
+            ino += 1;
+        }
+        None
+    }
+
+    fn read(&mut self, ino: InodeId, offset: uint, buf: &mut [u8]) -> uint {
+        let (data, size) = match self.entry(ino) {
+            Some(e) if !e.dir => (e.data, e.size),
+            _ => return 0
+        };
+        if offset >= size {
+            return 0;
+        }
+        let mut count = size - offset;
+        if count > buf.len() {
+            count = buf.len();
+        }
+        if count == 0 {
+            return 0;
+        }
+        unsafe {
+            copy_memory(&mut buf[0] as *mut u8, (self.base as uint + data + offset) as *u8, count);
+        }
+        count
+    }
+}
+
+/// The `i`th 8-digit hex field after the magic
+fn field(hdr: &[u8], i: uint) -> uint {
+    let mut v = 0;
+    let mut j = 6 + i * 8;
+    while j < 14 + i * 8 {
+        let c = hdr[j];
+        let d = if c >= '0' as u8 && c <= '9' as u8 {
+            c - '0' as u8
+        } else if c >= 'a' as u8 && c <= 'f' as u8 {
+            c - 'a' as u8 + 10
+        } else if c >= 'A' as u8 && c <= 'F' as u8 {
+            c - 'A' as u8 + 10
+        } else {
+            0
+        };
+        v = (v << 4) | d as uint;
+        j += 1;
+    }
+    v
+}
+
+fn align4(x: uint) -> uint {
+    (x + 3) & !3
+}
+
+unsafe fn offset(p: *mut Entry, i: uint) -> *mut Entry {
+    (p as uint + i * size_of::<Entry>()) as *mut Entry
+}
+
+unsafe fn bytes<'a>(p: *u8, len: uint) -> &'a [u8] {
+    transmute((p, len))
+}
@@ -0,0 +1,397 @@
+/* kernel::fs */
+/* Virtual filesystem: one namespace of absolute, '/'-separated paths over
+ * a table of mounted filesystems */
+
+use core::option::{Option, Some, None};
+use core::container::Container;
+use core::mem::transmute;
+use core::str::as_bytes;
+
+use self::ramfs::RamFs;
+use self::devfs::DevFs;
+
+pub mod ramfs;
+pub mod devfs;
+pub mod initrd;
+
+pub static NAME_MAX: uint = 32;
+pub static PATH_MAX: uint = 128;
+static MAX_MOUNTS: uint = 8;
+
+pub enum FileType {
+    Regular,
+    Directory,
+    Device
+}
+
+/// Identifies a node within one filesystem
+pub type InodeId = uint;
+
+pub struct Stat {
+    kind: FileType,
+    size: uint
+}
+
+pub struct DirEntry {
+    name: [u8, ..NAME_MAX],
+    name_len: uint,
+    kind: FileType
+}
+
+impl DirEntry {
+    pub fn new(name: &[u8], kind: FileType) -> DirEntry {
+        let mut e = DirEntry { name: [0, ..NAME_MAX], name_len: 0, kind: kind };
+        while e.name_len < name.len() && e.name_len < NAME_MAX {
+            e.name[e.name_len] = name[e.name_len];
+            e.name_len += 1;
+        }
+        e
+    }
+
+    pub fn name<'a>(&'a self) -> &'a [u8] {
+        sub(&self.name, 0, self.name_len)
+    }
+}
+
+/// A mountable filesystem. Operations a filesystem doesn't support fail
+/// by default, which makes it read-only.
+pub trait FileSystem {
+    fn root(&mut self) -> InodeId;
+    fn lookup(&mut self, dir: InodeId, name: &[u8]) -> Option<InodeId>;
+    fn stat(&mut self, ino: InodeId) -> Option<Stat>;
+    /// The `index`th entry of directory `dir`
+    fn readdir(&mut self, dir: InodeId, index: uint) -> Option<DirEntry>;
+    fn read(&mut self, ino: InodeId, offset: uint, buf: &mut [u8]) -> uint;
+
+    fn write(&mut self, _ino: InodeId, _offset: uint, _data: &[u8]) -> uint { 0 }
+    fn truncate(&mut self, _ino: InodeId) -> bool { false }
+    fn create(&mut self, _dir: InodeId, _name: &[u8], _kind: FileType) -> Option<InodeId> { None }
+    fn unlink(&mut self, _dir: InodeId, _name: &[u8]) -> bool { false }
+}
+
+/// A node in the global namespace: which mount, and which node in it
+pub struct Inode {
+    mount: uint,
+    id: InodeId
+}
+
+/// An open file with its own position
+pub struct File {
+    inode: Inode,
+    offset: uint
+}
+
+impl File {
+    pub fn read(&mut self, buf: &mut [u8]) -> uint {
+        let n = fs_of(self.inode.mount).read(self.inode.id, self.offset, buf);
+        self.offset += n;
+        n
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> uint {
+        let n = fs_of(self.inode.mount).write(self.inode.id, self.offset, data);
+        self.offset += n;
+        n
+    }
+
+    pub fn seek(&mut self, offset: uint) {
+        self.offset = offset;
+    }
+
+    /// Move to the end, so writes append.
+    pub fn seek_end(&mut self) {
+        self.offset = self.stat().size;
+    }
+
+    pub fn truncate(&mut self) -> bool {
+        self.offset = 0;
+        fs_of(self.inode.mount).truncate(self.inode.id)
+    }
+
+    pub fn stat(&self) -> Stat {
+        match fs_of(self.inode.mount).stat(self.inode.id) {
+            Some(st) => st,
+            None => Stat { kind: Regular, size: 0 }
+        }
+    }
+}
+
+struct Mount {
+    path: [u8, ..PATH_MAX],
+    len: uint,
+    fs: &'static mut FileSystem
+}
+
+static mut mounts: [Option<Mount>, ..MAX_MOUNTS] = [None, None, None, None, None, None, None, None];
+
+static mut root_fs: RamFs = RamFs { root: 0 as *mut ramfs::Node, slots: 0 as *mut ramfs::Slot, nslots: 0 };
+static mut dev_fs: DevFs = DevFs;
+
+/// Mount a RAM filesystem at / and devices at /dev.
+pub fn init() {
+    unsafe {
+        root_fs = RamFs::new();
+        mount(as_bytes("/"), &mut root_fs as &'static mut FileSystem);
+        mount(as_bytes("/dev"), &mut dev_fs as &'static mut FileSystem);
+    }
+}
+
+/// Attach `fs` at `path`, creating the mount point directory if the
+/// filesystem underneath allows it, so it shows up in listings.
+pub fn mount(path: &[u8], fs: &'static mut FileSystem) -> bool {
+    let mut canon = [0u8, ..PATH_MAX];
+    let len = match normalize(path, &mut canon) {
+        Some(len) => len,
+        None => return false
+    };
+    if len > 1 {
+        match stat(sub(&canon, 0, len)) {
+            Some(_) => (),
+            None => { mkdir(sub(&canon, 0, len)); }
+        }
+    }
+    unsafe {
+        let mut i = 0;
+        while i < MAX_MOUNTS {
+            match mounts[i] {
+                Some(_) => i += 1,
+                None => {
+                    mounts[i] = Some(Mount { path: canon, len: len, fs: fs });
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Open an existing file or device.
+pub fn open(path: &[u8]) -> Option<File> {
+    match resolve(path) {
+        Some(inode) => Some(File { inode: inode, offset: 0 }),
+        None => None
+    }
+}
+
+/// Create an empty file and open it. Fails if `path` exists.
+pub fn create(path: &[u8]) -> Option<File> {
+    match make(path, Regular) {
+        Some(inode) => Some(File { inode: inode, offset: 0 }),
+        None => None
+    }
+}
+
+pub fn mkdir(path: &[u8]) -> bool {
+    make(path, Directory).is_some()
+}
+
+/// Remove a file or empty directory. Mount points stay put.
+pub fn unlink(path: &[u8]) -> bool {
+    let mut canon = [0u8, ..PATH_MAX];
+    let len = match normalize(path, &mut canon) {
+        Some(len) => len,
+        None => return false
+    };
+    if is_mount_point(sub(&canon, 0, len)) {
+        return false;
+    }
+    match parent_of(sub(&canon, 0, len)) {
+        Some((dir, name)) => fs_of(dir.mount).unlink(dir.id, name),
+        None => false
+    }
+}
+
+pub fn stat(path: &[u8]) -> Option<Stat> {
+    match resolve(path) {
+        Some(inode) => fs_of(inode.mount).stat(inode.id),
+        None => None
+    }
+}
+
+/// The `index`th entry of the directory at `path`.
+pub fn readdir(path: &[u8], index: uint) -> Option<DirEntry> {
+    match resolve(path) {
+        Some(inode) => fs_of(inode.mount).readdir(inode.id, index),
+        None => None
+    }
+}
+
+/// Find the node at `path`.
+pub fn resolve(path: &[u8]) -> Option<Inode> {
+    let mut canon = [0u8, ..PATH_MAX];
+    let len = match normalize(path, &mut canon) {
+        Some(len) => len,
+        None => return None
+    };
+    let path = sub(&canon, 0, len);
+    let (mount, mut i) = match find_mount(path) {
+        Some(m) => m,
+        None => return None
+    };
+    let fs = fs_of(mount);
+    let mut id = fs.root();
+    while i < len {
+        let (start, end) = component(path, i);
+        i = end;
+        if start == end {
+            break;
+        }
+        match fs.lookup(id, sub(path, start, end)) {
+            Some(next) => id = next,
+            None => return None
+        }
+    }
+    Some(Inode { mount: mount, id: id })
+}
+
+fn make(path: &[u8], kind: FileType) -> Option<Inode> {
+    let mut canon = [0u8, ..PATH_MAX];
+    let len = match normalize(path, &mut canon) {
+        Some(len) => len,
+        None => return None
+    };
+    match parent_of(sub(&canon, 0, len)) {
+        Some((dir, name)) => match fs_of(dir.mount).create(dir.id, name, kind) {
+            Some(id) => Some(Inode { mount: dir.mount, id: id }),
+            None => None
+        },
+        None => None
+    }
+}
+
+/// Parent directory of a normalized path and the last component
+fn parent_of<'a>(path: &'a [u8]) -> Option<(Inode, &'a [u8])> {
+    let mut start = path.len();
+    while start > 0 && path[start - 1] != '/' as u8 {
+        start -= 1;
+    }
+    if start == path.len() {
+        // the root has no parent
+        return None;
+    }
+    let parent = if start <= 1 { sub(path, 0, 1) } else { sub(path, 0, start - 1) };
+    match resolve(parent) {
+        Some(dir) => Some((dir, sub(path, start, path.len()))),
+        None => None
+    }
+}
+
+/// Write the canonical form of `path` to `out`: absolute, no empty, "." or
+/// ".." components and no trailing slash. Returns its length.
+pub fn normalize(path: &[u8], out: &mut [u8, ..PATH_MAX]) -> Option<uint> {
+    out[0] = '/' as u8;
+    let mut len = 1;
+    let mut i = 0;
+    while i < path.len() {
+        let (start, end) = component(path, i);
+        i = end;
+        if start == end {
+            break;
+        }
+        let name = sub(path, start, end);
+        if eq(name, as_bytes(".")) {
+            continue;
+        }
+        if eq(name, as_bytes("..")) {
+            while len > 1 && out[len - 1] != '/' as u8 {
+                len -= 1;
+            }
+            if len > 1 {
+                len -= 1;
+            }
+            continue;
+        }
+        if len + name.len() + 1 > PATH_MAX {
+            return None;
+        }
+        if len > 1 {
+            out[len] = '/' as u8;
+            len += 1;
+        }
+        let mut j = 0;
+        while j < name.len() {
+            out[len] = name[j];
+            len += 1;
+            j += 1;
+        }
+    }
+    Some(len)
+}
+
+/// Longest mount covering a normalized path, and where the rest starts
+fn find_mount(path: &[u8]) -> Option<(uint, uint)> {
+    let mut best = None;
+    let mut best_len = 0;
+    unsafe {
+        let mut i = 0;
+        while i < MAX_MOUNTS {
+            match mounts[i] {
+                Some(ref m) => {
+                    let mp = sub(&m.path, 0, m.len);
+                    let covers = m.len == 1 || (path.len() >= m.len && eq(sub(path, 0, m.len), mp)
+                        && (path.len() == m.len || path[m.len] == '/' as u8));
+                    if covers && (best.is_none() || m.len > best_len) {
+                        best = Some(i);
+                        best_len = m.len;
+                    }
+                }
+                None => ()
+            }
+            i += 1;
+        }
+    }
+    match best {
+        Some(i) => Some((i, best_len)),
+        None => None
+    }
+}
+
+fn is_mount_point(path: &[u8]) -> bool {
+    match find_mount(path) {
+        Some((_, len)) => len == path.len(),
+        None => false
+    }
+}
+
+fn fs_of(mount: uint) -> &'static mut FileSystem {
+    unsafe {
+        match mounts[mount] {
+            Some(ref mut m) => &mut *m.fs,
+            None => kpanic!("fs: no such mount")
+        }
+    }
+}
+
+/// Bounds of the path component at or after `i`
+pub fn component(path: &[u8], mut i: uint) -> (uint, uint) {
+    while i < path.len() && path[i] == '/' as u8 {
+        i += 1;
+    }
+    let start = i;
+    while i < path.len() && path[i] != '/' as u8 {
+        i += 1;
+    }
+    (start, i)
+}
+
+/// `s[start..end]`
+pub fn sub<'a>(s: &'a [u8], start: uint, end: uint) -> &'a [u8] {
+    if start >= end {
+        return &[];
+    }
+    unsafe { transmute((&s[start] as *u8, end - start)) }
+}
+
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
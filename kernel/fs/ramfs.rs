@@ -0,0 +1,404 @@
+/* kernel::fs::ramfs */
+/* In-memory hierarchical filesystem. Nodes and file contents live on the
+ * kernel heap. Inode numbers index a table of live nodes and carry the
+ * slot's generation, so a file still open after `unlink` stops resolving
+ * instead of pointing at freed memory. */
+
+use core::option::{Option, Some, None};
+use core::container::Container;
+use core::mem::{size_of, transmute};
+use core::ptr::{copy_memory, set_memory};
+
+use kernel;
+use kernel::memory::Allocator;
+use kernel::fs::{FileSystem, FileType, Directory, Device, InodeId, Stat, DirEntry, NAME_MAX, eq};
+
+/// Low bits of an inode number pick the slot, the rest its generation
+static SLOT_BITS: uint = 16;
+static SLOT_MASK: uint = (1 << SLOT_BITS) - 1;
+
+pub struct RamFs {
+    root: *mut Node,
+    /// Node of each inode number; empty slots are reused
+    slots: *mut Slot,
+    nslots: uint
+}
+
+pub struct Slot {
+    node: *mut Node,
+    /// Bumped when the node is freed
+    gen: uint
+}
+
+pub struct Node {
+    name: [u8, ..NAME_MAX],
+    name_len: uint,
+    kind: FileType,
+    parent: *mut Node,
+    /// First entry of a directory
+    children: *mut Node,
+    /// Next entry in the parent directory
+    sibling: *mut Node,
+    /// File contents
+    data: *mut u8,
+    size: uint,
+    capacity: uint,
+    id: InodeId,
+}
+
+impl Node {
+    pub fn name<'a>(&'a self) -> &'a [u8] {
+        unsafe { bytes(&self.name[0] as *u8, self.name_len) }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        match self.kind {
+            Directory => true,
+            _ => false
+        }
+    }
+
+    pub fn size(&self) -> uint {
+        self.size
+    }
+}
+
+impl RamFs {
+    pub fn new() -> RamFs {
+        unsafe {
+            let root = new_node(Directory, &[]);
+            (*root).parent = root;
+            let mut fs = RamFs { root: root, slots: 0 as *mut Slot, nslots: 0 };
+            if !fs.assign(root) {
+                kpanic!("ramfs: out of memory");
+            }
+            fs
+        }
+    }
+
+    /// The live node numbered `ino`.
+    fn node(&self, ino: InodeId) -> Option<*mut Node> {
+        let i = ino & SLOT_MASK;
+        if i >= self.nslots {
+            return None;
+        }
+        unsafe {
+            let slot = slot(self.slots, i);
+            if (*slot).node as uint == 0 || (*slot).gen != ino >> SLOT_BITS {
+                None
+            } else {
+                Some((*slot).node)
+            }
+        }
+    }
+
+    /// Give `node` an inode number in a free slot, growing the table if
+    /// there is none.
+    unsafe fn assign(&mut self, node: *mut Node) -> bool {
+        let mut i = 0;
+        while i < self.nslots && (*slot(self.slots, i)).node as uint != 0 {
+            i += 1;
+        }
+        if i == self.nslots {
+            let count = if self.nslots == 0 { 16 } else { self.nslots * 2 };
+            if count > SLOT_MASK + 1 {
+                return false;
+            }
+            let (slots, capacity) = kernel::heap.zero_alloc(count * size_of::<Slot>());
+            if capacity == 0 {
+                return false;
+            }
+            if self.nslots > 0 {
+                copy_memory(slots, self.slots as *u8, self.nslots * size_of::<Slot>());
+                kernel::heap.free(self.slots as *mut u8);
+            }
+            self.slots = slots as *mut Slot;
+            self.nslots = count;
+        }
+        let slot = slot(self.slots, i);
+        (*slot).node = node;
+        (*node).id = (*slot).gen << SLOT_BITS | i;
+        true
+    }
+
+    /// Retire the inode number of a freed node.
+    unsafe fn release(&mut self, ino: InodeId) {
+        let slot = slot(self.slots, ino & SLOT_MASK);
+        (*slot).node = 0 as *mut Node;
+        (*slot).gen = ((*slot).gen + 1) & (!0u >> SLOT_BITS);
+    }
+}
+
+impl FileSystem for RamFs {
+    fn root(&mut self) -> InodeId {
+        unsafe { (*self.root).id }
+    }
+
+    fn lookup(&mut self, dir: InodeId, name: &[u8]) -> Option<InodeId> {
+        unsafe {
+            let dir = match self.node(dir) {
+                Some(dir) => dir,
+                None => return None
+            };
+            if !(*dir).is_dir() {
+                return None;
+            }
+            find_child(dir, name).map(|n| (*n).id)
+        }
+    }
+
+    fn stat(&mut self, ino: InodeId) -> Option<Stat> {
+        unsafe {
+            self.node(ino).map(|n| Stat { kind: (*n).kind, size: (*n).size })
+        }
+    }
+
+    fn readdir(&mut self, dir: InodeId, index: uint) -> Option<DirEntry> {
+        let dir = match self.node(dir) {
+            Some(dir) => dir,
+            None => return None
+        };
+        unsafe {
+            readdir(dir, index).map(|n| DirEntry::new((*n).name(), (*n).kind))
+        }
+    }
+
+    fn read(&mut self, ino: InodeId, offset: uint, buf: &mut [u8]) -> uint {
+        match self.node(ino) {
+            Some(file) => read(file, offset, buf),
+            None => 0
+        }
+    }
+
+    fn write(&mut self, ino: InodeId, offset: uint, data: &[u8]) -> uint {
+        match self.node(ino) {
+            Some(file) => write(file, offset, data),
+            None => 0
+        }
+    }
+
+    fn truncate(&mut self, ino: InodeId) -> bool {
+        match self.node(ino) {
+            Some(file) => { truncate(file); true }
+            None => false
+        }
+    }
+
+    fn create(&mut self, dir: InodeId, name: &[u8], kind: FileType) -> Option<InodeId> {
+        let dir = match self.node(dir) {
+            Some(dir) => dir,
+            None => return None
+        };
+        match kind {
+            Device => None,
+            _ => unsafe {
+                let node = match make(dir, name, kind) {
+                    Some(node) => node,
+                    None => return None
+                };
+                if self.assign(node) {
+                    Some((*node).id)
+                } else {
+                    unlink(node);
+                    None
+                }
+            }
+        }
+    }
+
+    fn unlink(&mut self, dir: InodeId, name: &[u8]) -> bool {
+        let dir = match self.node(dir) {
+            Some(dir) => dir,
+            None => return false
+        };
+        unsafe {
+            match find_child(dir, name) {
+                Some(node) => {
+                    let ino = (*node).id;
+                    if unlink(node) {
+                        self.release(ino);
+                        true
+                    } else {
+                        false
+                    }
+                }
+                None => false
+            }
+        }
+    }
+}
+
+/// Copy up to `buf.len()` bytes from `offset` in `file`. Returns the
+/// number of bytes read.
+pub fn read(file: *mut Node, offset: uint, buf: &mut [u8]) -> uint {
+    unsafe {
+        if (*file).is_dir() || offset >= (*file).size {
+            return 0;
+        }
+        let mut count = (*file).size - offset;
+        if count > buf.len() {
+            count = buf.len();
+        }
+        if count > 0 {
+            copy_memory(&mut buf[0] as *mut u8,
+                        ((*file).data as uint + offset) as *u8, count);
+        }
+        count
+    }
+}
+
+/// Write `data` at `offset` in `file`, growing it as needed; a gap past
+/// the old end reads as zeros. Returns the number of bytes written.
+pub fn write(file: *mut Node, offset: uint, data: &[u8]) -> uint {
+    unsafe {
+        if (*file).is_dir() {
+            return 0;
+        }
+        let end = offset + data.len();
+        if !reserve(file, end) {
+            return 0;
+        }
+        if offset > (*file).size {
+            set_memory(((*file).data as uint + (*file).size) as *mut u8, 0,
+                       offset - (*file).size);
+        }
+        if data.len() > 0 {
+            copy_memory(((*file).data as uint + offset) as *mut u8,
+                        &data[0] as *u8, data.len());
+        }
+        if end > (*file).size {
+            (*file).size = end;
+        }
+        data.len()
+    }
+}
+
+pub fn append(file: *mut Node, data: &[u8]) -> uint {
+    unsafe { write(file, (*file).size, data) }
+}
+
+/// Drop the contents of `file`.
+pub fn truncate(file: *mut Node) {
+    unsafe { (*file).size = 0; }
+}
+
+/// Remove a file or an empty directory.
+pub fn unlink(node: *mut Node) -> bool {
+    unsafe {
+        if node == (*node).parent {
+            return false;
+        }
+        if (*node).is_dir() && (*node).children as uint != 0 {
+            return false;
+        }
+        let parent = (*node).parent;
+        if (*parent).children == node {
+            (*parent).children = (*node).sibling;
+        } else {
+            let mut prev = (*parent).children;
+            while (*prev).sibling != node {
+                prev = (*prev).sibling;
+            }
+            (*prev).sibling = (*node).sibling;
+        }
+        if (*node).data as uint != 0 {
+            kernel::heap.free((*node).data);
+        }
+        kernel::free(node as *mut u8);
+        true
+    }
+}
+
+/// The `index`th entry of directory `dir`.
+pub fn readdir(dir: *mut Node, index: uint) -> Option<*mut Node> {
+    unsafe {
+        if !(*dir).is_dir() {
+            return None;
+        }
+        let mut node = (*dir).children;
+        let mut i = 0;
+        while node as uint != 0 {
+            if i == index {
+                return Some(node);
+            }
+            node = (*node).sibling;
+            i += 1;
+        }
+        None
+    }
+}
+
+/// Add an empty node called `name` to `parent`. Fails if it exists.
+fn make(parent: *mut Node, name: &[u8], kind: FileType) -> Option<*mut Node> {
+    unsafe {
+        if !(*parent).is_dir() || name.len() == 0 || name.len() > NAME_MAX {
+            return None;
+        }
+        match find_child(parent, name) {
+            Some(_) => return None,
+            None => ()
+        }
+        let node = new_node(kind, name);
+        (*node).parent = parent;
+        // Keep entries in creation order
+        if (*parent).children as uint == 0 {
+            (*parent).children = node;
+        } else {
+            let mut last = (*parent).children;
+            while (*last).sibling as uint != 0 {
+                last = (*last).sibling;
+            }
+            (*last).sibling = node;
+        }
+        Some(node)
+    }
+}
+
+unsafe fn find_child(dir: *mut Node, name: &[u8]) -> Option<*mut Node> {
+    let mut node = (*dir).children;
+    while node as uint != 0 {
+        if eq((*node).name(), name) {
+            return Some(node);
+        }
+        node = (*node).sibling;
+    }
+    None
+}
+
+unsafe fn new_node(kind: FileType, name: &[u8]) -> *mut Node {
+    let node = kernel::zero_alloc(size_of::<Node>()) as *mut Node;
+    (*node).kind = kind;
+    let mut i = 0;
+    while i < name.len() {
+        (*node).name[i] = name[i];
+        i += 1;
+    }
+    (*node).name_len = name.len();
+    node
+}
+
+/// Make room for `size` bytes of contents.
+unsafe fn reserve(file: *mut Node, size: uint) -> bool {
+    if size <= (*file).capacity {
+        return true;
+    }
+    let (data, capacity) = kernel::heap.alloc(size);
+    if capacity == 0 {
+        return false;
+    }
+    if (*file).data as uint != 0 {
+        copy_memory(data, (*file).data as *u8, (*file).size);
+        kernel::heap.free((*file).data);
+    }
+    (*file).data = data;
+    (*file).capacity = capacity;
+    true
+}
+
+unsafe fn slot(slots: *mut Slot, i: uint) -> *mut Slot {
+    (slots as uint + i * size_of::<Slot>()) as *mut Slot
+}
+
+unsafe fn bytes<'a>(p: *u8, len: uint) -> &'a [u8] {
+    transmute((p, len))
+}
@@ -0,0 +1,333 @@
+/* kernel::log */
+/* Kernel messages. Every record goes to an in-memory ring buffer (see
+ * `dmesg` in the shell); records at or above the console level also go
+ * to the registered sinks. A record is built on the caller's stack and
+ * lands in the buffers whole, so interrupts are only masked for the copy,
+ * never while a sink does I/O. */
+
+use core::option::{Option, Some, None};
+use core::container::Container;
+use core::str::as_bytes;
+
+use kernel::cmdline;
+use kernel::fs::sub;
+use kernel::int::to_str_bytes;
+use kernel::time;
+use platform::cpu::interrupt;
+use platform::drivers::board;
+
+pub enum Level {
+    Error = 0,
+    Warning = 1,
+    Info = 2,
+    Debug = 3
+}
+
+static LOG_BUF_SIZE: uint = 4096;
+/// Console output not yet handed to the sinks
+static OUTBOX_SIZE: uint = 1024;
+/// Longest record, timestamp and newline included; the rest is cut off
+static RECORD_MAX: uint = 192;
+static MAX_SINKS: uint = 4;
+
+/// Receives the text of records one byte at a time
+pub type Sink = fn(u8);
+
+static mut ring: [u8, ..LOG_BUF_SIZE] = [0, ..LOG_BUF_SIZE];
+/// Bytes ever written; the ring holds the last LOG_BUF_SIZE of them
+static mut written: uint = 0;
+static mut sinks: [Option<Sink>, ..MAX_SINKS] = [None, ..MAX_SINKS];
+static mut console_level: uint = 2; // Info
+static mut outbox: [u8, ..OUTBOX_SIZE] = [0, ..OUTBOX_SIZE];
+static mut outbox_head: uint = 0;
+static mut outbox_count: uint = 0;
+/// Set while some caller is feeding the outbox to the sinks
+static mut draining: bool = false;
+
+/// A record being written
+pub struct Record {
+    level: uint,
+    buf: [u8, ..RECORD_MAX],
+    len: uint
+}
+
+/// `klog!(level, parts...)` logs each part in turn, then a newline. Parts
+/// can be strings, byte slices, integers or `log::Hex(x)`.
+macro_rules! klog(
+    ($level:expr, $($arg:expr),+) => ({
+        let mut record = ::kernel::log::begin($level);
+        $( ::kernel::log::put(&mut record, &$arg); )+
+        ::kernel::log::end(&mut record);
+    })
+)
+
+macro_rules! kerror( ($($arg:expr),+) => ( klog!(::kernel::log::Error, $($arg),+) ) )
+macro_rules! kwarn( ($($arg:expr),+) => ( klog!(::kernel::log::Warning, $($arg),+) ) )
+macro_rules! kinfo( ($($arg:expr),+) => ( klog!(::kernel::log::Info, $($arg),+) ) )
+macro_rules! kdebug( ($($arg:expr),+) => ( klog!(::kernel::log::Debug, $($arg),+) ) )
+
+/// Something that can be written into a log record
+pub trait Loggable {
+    fn log(&self, r: &mut Record);
+}
+
+/// Logs a number in hexadecimal
+pub struct Hex(u32);
+
+/// Send records to the serial console right away, before anything else
+/// is up, and take the console level from `loglevel=`.
+pub fn init() {
+    unsafe {
+        match cmdline::get_uint("loglevel") {
+            Some(l) => console_level = l,
+            None => ()
+        }
+    }
+    add_sink(uart_sink);
+}
+
+/// Pass records at or above the console level to `sink` from now on.
+pub fn add_sink(sink: Sink) -> Option<uint> {
+    unsafe {
+        let mut i = 0;
+        while i < MAX_SINKS {
+            match sinks[i] {
+                Some(_) => i += 1,
+                None => {
+                    sinks[i] = Some(sink);
+                    return Some(i);
+                }
+            }
+        }
+    }
+    None
+}
+
+pub fn remove_sink(handle: uint) {
+    if handle < MAX_SINKS {
+        unsafe { sinks[handle] = None; }
+    }
+}
+
+/// Feed the contents of the ring buffer, oldest first, to `f`. The ring
+/// is copied out a piece at a time, so `f` runs with interrupts enabled;
+/// records logged meanwhile aren't included.
+pub fn dump(f: |&[u8]|) {
+    let mut chunk = [0u8, ..64];
+    let cpsr = interrupt::disable();
+    let stop = unsafe { written };
+    interrupt::restore(cpsr);
+    let mut pos = 0;
+    loop {
+        let cpsr = interrupt::disable();
+        let mut n = 0;
+        unsafe {
+            // Skip what was overwritten while `f` ran
+            if written > LOG_BUF_SIZE && pos < written - LOG_BUF_SIZE {
+                pos = written - LOG_BUF_SIZE;
+            }
+            while pos < stop && n < chunk.len() {
+                chunk[n] = ring[pos % LOG_BUF_SIZE];
+                pos += 1;
+                n += 1;
+            }
+        }
+        interrupt::restore(cpsr);
+        if n == 0 {
+            break;
+        }
+        f(sub(&chunk, 0, n));
+    }
+}
+
+/// Start a record at `level` with its timestamp.
+pub fn begin(level: Level) -> Record {
+    let mut r = Record { level: level as uint, buf: [0, ..RECORD_MAX], len: 0 };
+    let ms = time::uptime_ms() as uint;
+    r.push('[' as u8);
+    let secs = ms / 1000;
+    let frac = ms % 1000;
+    let mut pad = 100000;
+    while pad > 1 && secs < pad {
+        r.push(' ' as u8);
+        pad /= 10;
+    }
+    secs.log(&mut r);
+    r.push('.' as u8);
+    r.push('0' as u8 + (frac / 100) as u8);
+    r.push('0' as u8 + (frac / 10 % 10) as u8);
+    r.push('0' as u8 + (frac % 10) as u8);
+    "] ".log(&mut r);
+    r
+}
+
+/// Finish `r` and pass it on: into the ring, and to the sinks if it is
+/// at or above the console level.
+pub fn end(r: &mut Record) {
+    r.buf[r.len] = '\n' as u8;
+    r.len += 1;
+    let cpsr = interrupt::disable();
+    unsafe {
+        let mut i = 0;
+        while i < r.len {
+            ring[written % LOG_BUF_SIZE] = r.buf[i];
+            written += 1;
+            i += 1;
+        }
+        if r.level <= console_level {
+            i = 0;
+            while i < r.len {
+                // If the sinks have fallen that far behind, drop the rest
+                if outbox_count < OUTBOX_SIZE {
+                    outbox[(outbox_head + outbox_count) % OUTBOX_SIZE] = r.buf[i];
+                    outbox_count += 1;
+                }
+                i += 1;
+            }
+        }
+    }
+    interrupt::restore(cpsr);
+    drain();
+}
+
+pub fn put<T: Loggable>(r: &mut Record, x: &T) {
+    x.log(r);
+}
+
+impl Record {
+    /// Append `c`, keeping room for the newline.
+    fn push(&mut self, c: u8) {
+        if self.len < RECORD_MAX - 1 {
+            self.buf[self.len] = c;
+            self.len += 1;
+        }
+    }
+}
+
+/// Hand the outbox to the sinks in order. Only one caller does this at a
+/// time, with interrupts as the caller had them; a record logged while
+/// another caller is draining, say from an interrupt handler, is sent by
+/// that caller.
+fn drain() {
+    let mut chunk = [0u8, ..32];
+    let cpsr = interrupt::disable();
+    unsafe {
+        if draining {
+            interrupt::restore(cpsr);
+            return;
+        }
+        draining = true;
+    }
+    loop {
+        let mut n = 0;
+        unsafe {
+            while outbox_count > 0 && n < chunk.len() {
+                chunk[n] = outbox[outbox_head];
+                outbox_head = (outbox_head + 1) % OUTBOX_SIZE;
+                outbox_count -= 1;
+                n += 1;
+            }
+            if n == 0 {
+                draining = false;
+            }
+        }
+        interrupt::restore(cpsr);
+        if n == 0 {
+            return;
+        }
+        let mut i = 0;
+        while i < n {
+            send(chunk[i]);
+            i += 1;
+        }
+        interrupt::disable();
+    }
+}
+
+fn send(c: u8) {
+    unsafe {
+        let mut i = 0;
+        while i < MAX_SINKS {
+            match sinks[i] {
+                Some(f) => f(c),
+                None => ()
+            }
+            i += 1;
+        }
+    }
+}
+
+/// The console port once it is open, so records queue behind the shell's
+/// output rather than overtaking it; polled output before that.
+fn uart_sink(c: u8) {
+    let port = board().console();
+    if port.isOpen() {
+        port.write(c);
+    } else {
+        board().putc_raw(c);
+    }
+}
+
+impl<'a> Loggable for &'a str {
+    fn log(&self, r: &mut Record) {
+        let b = as_bytes(*self);
+        let mut i = 0;
+        while i < b.len() {
+            r.push(b[i]);
+            i += 1;
+        }
+    }
+}
+
+impl<'a> Loggable for &'a [u8] {
+    fn log(&self, r: &mut Record) {
+        let mut i = 0;
+        while i < self.len() {
+            r.push((*self)[i]);
+            i += 1;
+        }
+    }
+}
+
+impl Loggable for int {
+    fn log(&self, r: &mut Record) {
+        to_str_bytes(*self, 10, |c| r.push(c));
+    }
+}
+
+impl Loggable for uint {
+    fn log(&self, r: &mut Record) {
+        if (*self as int) < 0 {
+            // Too big for to_str_bytes; peel off the last digit
+            (*self / 10).log(r);
+            r.push('0' as u8 + (*self % 10) as u8);
+        } else {
+            to_str_bytes(*self as int, 10, |c| r.push(c));
+        }
+    }
+}
+
+impl Loggable for u32 {
+    fn log(&self, r: &mut Record) {
+        (*self as uint).log(r);
+    }
+}
+
+impl Loggable for i32 {
+    fn log(&self, r: &mut Record) {
+        (*self as int).log(r);
+    }
+}
+
+impl Loggable for Hex {
+    fn log(&self, r: &mut Record) {
+        let Hex(x) = *self;
+        "0x".log(r);
+        let mut shift = 32;
+        while shift > 0 {
+            shift -= 4;
+            let d = ((x >> shift) & 0xF) as u8;
+            r.push(if d < 10 { '0' as u8 + d } else { 'a' as u8 + d - 10 });
+        }
+    }
+}
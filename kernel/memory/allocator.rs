@@ -3,6 +3,7 @@ use core::ptr::{set_memory, copy_memory, offset};
 use core::i32::ctlz32;
 
 use kernel::ptr::mut_offset;
+use platform::cpu::interrupt;
 
 #[repr(u8)]
 enum Node {
@@ -210,9 +211,13 @@ impl BuddyAlloc {
     }
 }
 
+// Tasks are preempted from the tick, so the tree is only touched with IRQs
+// masked; a switch halfway through would hand the next task a broken tree.
 impl Allocator for Alloc {
     fn alloc(&mut self, size: uint) -> (*mut u8, uint) {
+        let irq = interrupt::disable();
         let (offset, size) = self.parent.alloc(size);
+        interrupt::restore(irq);
         unsafe {
             return (
                 mut_offset(self.base, (offset << self.el_size) as int),
@@ -231,6 +236,8 @@ impl Allocator for Alloc {
         }
 
         let offset = (ptr as uint - self.base as uint) >> self.el_size;
+        let irq = interrupt::disable();
         self.parent.free(offset);
+        interrupt::restore(irq);
     }
 }
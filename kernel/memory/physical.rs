@@ -1,8 +1,9 @@
-use core::fail::abort;
+use core::option::{Option, Some, None};
 
 use kernel;
 use kernel::memory;
 use kernel::memory::Allocator;
+use kernel::log::Hex;
 
 pub static mut frames: memory::Alloc = memory::Alloc {
     base: 0x200_000 as *mut u8,
@@ -13,22 +14,67 @@ pub static mut frames: memory::Alloc = memory::Alloc {
     }
 };
 
-pub fn init() {
+/// The tree for order 13 fills the one frame we give it
+static MAX_ORDER: uint = 13;
+
+/// End of RAM, which may be past the end of the frame region
+static mut ram_end: uint = 0;
+
+/// Set up the frame allocator to cover as much of [base, mem_end) as a
+/// power of two of frames allows. Without a known end, assume the region
+/// is MAX_ORDER in size and that RAM ends with it.
+pub fn init(mem_end: Option<uint>) {
     unsafe {
         frames.parent.tree.storage = kernel::zero_alloc(0x1000) as memory::BitvStorage;
+        match mem_end {
+            Some(end) if end > frames.base as uint => {
+                let count = (end - frames.base as uint) >> frames.el_size;
+                let mut order = 0;
+                while order < MAX_ORDER && (2 << order) <= count {
+                    order += 1;
+                }
+                frames.parent.order = order;
+                ram_end = end;
+            }
+            _ => ram_end = limit()
+        }
+        kinfo!("physical: ", 1u << frames.parent.order, " frames at ", Hex(frames.base as u32));
     }
 }
 
+/// End of the frame region
+pub fn limit() -> uint {
+    unsafe { frames.base as uint + (1 << frames.parent.order << frames.el_size) }
+}
+
+/// End of RAM. Memory between `limit` and here is mapped by the MMU but
+/// not handed out as frames.
+pub fn ram_end() -> uint {
+    unsafe { ram_end }
+}
+
 pub unsafe fn alloc_frames(count: uint) -> *mut u8 {
     match frames.alloc(count) {
-        (_, 0) => abort(),
+        (_, 0) => kpanic!("out of physical frames"),
         (ptr, _) => ptr
     }
 }
 
 pub unsafe fn zero_alloc_frames(count: uint) -> *mut u8 {
+    match try_zero_alloc_frames(count) {
+        Some(ptr) => ptr,
+        None => kpanic!("out of physical frames")
+    }
+}
+
+/// `zero_alloc_frames` for callers that can cope with running out
+pub unsafe fn try_zero_alloc_frames(count: uint) -> Option<*mut u8> {
     match frames.zero_alloc(count) {
-        (_, 0) => abort(),
-        (ptr, _) => ptr
+        (_, 0) => None,
+        (ptr, _) => Some(ptr)
     }
 }
+
+pub unsafe fn free_frames(ptr: *mut u8) {
+    frames.free(ptr);
+}
@@ -1 +1,123 @@
-pub use cpu::mmu::{map, PageDirectory, RW, USER};
+pub use cpu::mmu::{map, unmap, translate, PageDirectory, RW, USER, PAGE_SIZE};
+pub use cpu::mmu::{USER_BASE, USER_LIMIT};
+
+use core::option::{Option, Some, None};
+
+use cpu::mmu;
+use kernel::memory::physical;
+
+/// A task's view of memory: the shared kernel mappings plus private pages
+/// in [USER_BASE, USER_LIMIT).
+pub struct AddressSpace {
+    dir: *mut PageDirectory
+}
+
+impl AddressSpace {
+    pub fn new() -> AddressSpace {
+        unsafe { AddressSpace { dir: PageDirectory::new() } }
+    }
+
+    /// The kernel's own directory. Destroying it does nothing.
+    pub fn kernel() -> AddressSpace {
+        unsafe { AddressSpace { dir: mmu::kernel_dir } }
+    }
+
+    pub fn is_kernel(&self) -> bool {
+        unsafe { self.dir == mmu::kernel_dir }
+    }
+
+    /// Whether both use the same page directory
+    pub fn same_as(&self, other: &AddressSpace) -> bool {
+        self.dir == other.dir
+    }
+
+    pub fn clone(&self) -> AddressSpace {
+        unsafe { AddressSpace { dir: (*self.dir).clone() } }
+    }
+
+    pub fn destroy(&mut self) {
+        if !self.is_kernel() {
+            unsafe { PageDirectory::destroy(self.dir); }
+            self.dir = unsafe { mmu::kernel_dir };
+        }
+    }
+
+    /// Map a user page. Fails outside the user region, or if there is no
+    /// frame left for its page table.
+    pub fn map(&mut self, virt: u32, phys: u32, writable: bool) -> bool {
+        if self.is_kernel() || virt < USER_BASE || virt >= USER_LIMIT {
+            return false;
+        }
+        // RW keeps supervisor access; USER alone makes it read-only for user mode
+        let flags = if writable { RW | USER } else { USER };
+        unsafe { (*self.dir).try_map(virt, phys, flags) }
+    }
+
+    /// Back `count` pages from `virt` with fresh zeroed frames. If that
+    /// fails partway, e.g. for lack of frames, the pages already backed
+    /// are released again.
+    pub fn alloc(&mut self, virt: u32, count: uint, writable: bool) -> bool {
+        let mut i = 0;
+        while i < count {
+            let page = virt + (i as u32) * PAGE_SIZE;
+            let ok = match unsafe { physical::try_zero_alloc_frames(1) } {
+                Some(frame) => {
+                    let mapped = self.map(page, frame as u32, writable);
+                    if !mapped {
+                        unsafe { physical::free_frames(frame); }
+                    }
+                    mapped
+                }
+                None => false
+            };
+            if !ok {
+                self.release(virt, i);
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    /// Unmap `count` user pages from `virt` and free their frames.
+    fn release(&mut self, virt: u32, count: uint) {
+        let mut i = 0;
+        while i < count {
+            let page = virt + (i as u32) * PAGE_SIZE;
+            match self.translate(page) {
+                Some(phys) => unsafe {
+                    (*self.dir).unmap(page);
+                    physical::free_frames(phys as *mut u8);
+                },
+                None => ()
+            }
+            i += 1;
+        }
+    }
+
+    /// Whether user mode may read all of [start, start + len), and write
+    /// it too if `write` is set.
+    pub fn user_accessible(&self, start: u32, len: u32, write: bool) -> bool {
+        if start < USER_BASE || len > USER_LIMIT - start {
+            return false;
+        }
+        let need = if write { RW | USER } else { USER };
+        let mut page = start & !(PAGE_SIZE - 1);
+        while page < start + len {
+            match unsafe { (*self.dir).access(page) } {
+                Some(flags) if flags & need == need => page += PAGE_SIZE,
+                _ => return false
+            }
+        }
+        true
+    }
+
+    pub fn translate(&self, virt: u32) -> Option<u32> {
+        unsafe { (*self.dir).translate(virt) }
+    }
+
+    /// Load this space into the MMU.
+    pub fn activate(&self) {
+        unsafe { (*self.dir).enable(); }
+    }
+}
@@ -1,5 +1,4 @@
 use core::option::{Option, Some, None};
-use core::fail::out_of_memory;
 
 use platform::{cpu, drivers};
 //use platform::{io};
@@ -7,7 +6,18 @@ use cpu::interrupt;
 
 //use self::memory::virtual::PageDirectory;
 use self::memory::Allocator;
-
+use self::shell::Shell;
+
+#[macro_escape]
+pub mod panic;
+#[macro_escape]
+pub mod log;
+
+pub mod board;
+pub mod boot;
+pub mod cmdline;
+pub mod device;
+pub mod fs;
 pub mod int;
 pub mod ptr;
 pub mod memory;
@@ -16,6 +26,9 @@ pub mod sgash;
 pub mod screen;
 pub mod serial;
 pub mod shell;
+pub mod syscall;
+pub mod task;
+pub mod time;
 
 #[cfg(target_word_size = "32")]
 pub mod rt;
@@ -35,8 +48,19 @@ pub static mut int_table: Option<interrupt::Table> = None;
 #[no_mangle]
 pub fn main() 
 {
+    boot::init();
+    log::init();
+    kinfo!("ironkernel: cmdline \"", boot::cmdline(), "\"");
     memory::BuddyAlloc::new(17, memory::Bitv { storage: 0x100_000 as memory::BitvStorage });
-    memory::physical::init();
+    memory::physical::init(match boot::mem_end() {
+        Some(end) => Some(end),
+        None => match drivers::board().default_ram() {
+            Some((base, size)) => Some(base + size),
+            None => kpanic!("boot loader did not say how much RAM there is")
+        }
+    });
+    fs::init();
+    fs::initrd::init();
     let table = interrupt::Table::new();
     unsafe {
         int_table = Some(table);
@@ -45,21 +69,53 @@ pub fn main()
 
     table.load();
     drivers::init();
-    // Not for RPi
-    /*unsafe {
-        drivers::keydown = Some(sgash::parsekey);
-        io::init(640, 480);
-    }*/
+    task::init();
+    time::init();
+    start_shell();
 }
 
 
-#[cfg(target_chip = "arm1176jzf-s")]
+/// Stop the tick and every device, then halt.
+pub fn shutdown() -> !
+{
+    kinfo!("ironkernel: shutting down");
+    drivers::board().timer_stop();
+    device::shutdown_all();
+    panic::halt()
+}
+
+/// Bring up the console shell on the screen, keyboard and serial port,
+/// run the `init=` script if there is one, then start the shell task on
+/// whatever input has queued up meanwhile.
 fn start_shell()
 {
-    let shell : sgash::SGASH;
-    shell.init();
-    shell.attachToScreen(drivers::chip::screen::Screen0);
-    shell.attachToSerial(drivers::chip::serial::UART0);
+    unsafe {
+        let shell = &mut sgash::console;
+        shell.init();
+        let port = match serial::console_port() {
+            Some(i) => drivers::board().serial(i),
+            None => None
+        };
+        match port {
+            Some(port) => if !shell.attachToSerial(port) {
+                kwarn!("sgash: cannot attach to the serial console");
+            },
+            None => ()
+        }
+        match drivers::board().display() {
+            Some(scr) => { shell.attachToScreen(scr); }
+            None => ()
+        }
+        drivers::keydown = Some(sgash::keyInput);
+        match cmdline::get("init") {
+            Some(path) => if !shell.runScript(path) {
+                kwarn!("sgash: cannot run init script ", path);
+            },
+            None => ()
+        }
+        shell.prompt();
+    }
+    task::spawn(sgash::run);
 }
 
 
@@ -73,7 +129,7 @@ pub unsafe fn malloc_raw(size: uint) -> *mut u8
     else {
         let (ptr, sz) = heap.alloc(size);
         if sz == 0 {
-            out_of_memory();
+            kpanic!("out of memory");
         }
         ptr
     }
@@ -95,7 +151,7 @@ pub unsafe fn zero_alloc(size: uint) -> *mut u8
     else {
         let (ptr, sz) = heap.zero_alloc(size);
         if sz == 0 {
-            out_of_memory();
+            kpanic!("out of memory");
         }
         ptr
     }
@@ -108,9 +164,12 @@ pub unsafe fn realloc_raw(ptr: *mut u8, size: uint) -> *mut u8
         free(ptr);
         0 as *mut u8
     } else {
+        // The old block is freed before its contents are copied out
+        let irq = interrupt::disable();
         let (ptr, sz) = heap.realloc(ptr, size);
+        interrupt::restore(irq);
         if sz == 0 {
-            out_of_memory()
+            kpanic!("out of memory");
         }
         ptr
     }
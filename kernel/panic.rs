@@ -0,0 +1,242 @@
+/* kernel::panic */
+/* Fatal error reports on the serial console and screen */
+
+use core::option::{Option, Some, None};
+use core::mem::{size_of, transmute};
+use core::slice;
+use core::str::as_bytes;
+
+use kernel::memory::physical;
+use kernel::screen::TerminalCanvas;
+use platform::cpu::interrupt::Context;
+use platform::drivers::board;
+
+/// Deepest backtrace printed
+static MAX_FRAMES: uint = 16;
+
+static MODE_MASK: u32 = 0x1F;
+static MODE_USR: u32 = 0x10;
+
+macro_rules! kpanic(
+    ($msg:expr) => (
+        ::kernel::panic::panic($msg, file!(), line!())
+    )
+)
+
+macro_rules! kassert(
+    ($cond:expr) => (
+        if !$cond {
+            ::kernel::panic::panic(concat!("assertion failed: ", stringify!($cond)),
+                                   file!(), line!())
+        }
+    )
+)
+
+/// Report `msg` with the state of the caller and stop. Use `kpanic!`,
+/// which fills in the file and line.
+#[inline(never)]
+pub fn panic(msg: &str, file: &str, line: uint) -> ! {
+    stop(msg, None, file, line)
+}
+
+/// Report indexing at `index` into something `len` long and stop.
+#[inline(never)]
+pub fn panic_bounds(index: uint, len: uint, file: &str, line: uint) -> ! {
+    stop("index out of bounds", Some((index, len)), file, line)
+}
+
+/// A NUL-terminated string from the compiler, e.g. a file name
+pub unsafe fn c_str(p: *u8) -> &'static str {
+    if p as uint == 0 {
+        return "";
+    }
+    let mut len = 0;
+    while *((p as uint + len) as *u8) != 0 {
+        len += 1;
+    }
+    transmute((p, len))
+}
+
+fn stop(msg: &str, bounds: Option<(uint, uint)>, file: &str, line: uint) -> ! {
+    let mut regs = [0u32, ..16];
+    let cpsr: u32;
+    unsafe {
+        asm!("stmia $0, {r0-r12}
+              str sp, [$0, #52]
+              str lr, [$0, #56]
+              str pc, [$0, #60]"
+            :: "r"(&mut regs as *mut [u32, ..16]) : "memory");
+        asm!("mrs $0, cpsr" : "=r"(cpsr));
+    }
+    let ctx = Context {
+        usr_sp: 0,
+        usr_lr: 0,
+        spsr: cpsr,
+        r: [regs[0], regs[1], regs[2], regs[3], regs[4], regs[5], regs[6],
+            regs[7], regs[8], regs[9], regs[10], regs[11], regs[12]],
+        lr: regs[14],
+        pc: regs[15],
+    };
+
+    puts("\n*** kernel panic: ");
+    puts(msg);
+    match bounds {
+        Some((index, len)) => {
+            puts(": index ");
+            put_dec(index as u32);
+            puts(", len ");
+            put_dec(len as u32);
+        }
+        None => ()
+    }
+    puts("\n    at ");
+    puts(file);
+    puts(":");
+    put_dec(line as u32);
+    puts("\n");
+    report(&ctx, regs[13]);
+    halt()
+}
+
+/// Stop after an unrecoverable exception that saved `ctx`.
+pub fn panic_context(msg: &str, ctx: &Context) -> ! {
+    puts("\n*** kernel panic: ");
+    puts(msg);
+    puts("\n");
+    report(ctx, saved_sp(ctx));
+    halt()
+}
+
+/// Mask interrupts and wait forever.
+pub fn halt() -> ! {
+    unsafe {
+        asm!("mrs r0, cpsr
+              orr r0, r0, #0xC0
+              msr cpsr_c, r0" ::: "r0");
+        loop {
+            // wait for interrupt; with everything masked this is just a nap
+            asm!("mov r0, 0
+                  mcr p15, 0, r0, c7, c0, 4" ::: "r0");
+        }
+    }
+}
+
+/// Entry for `core::fail::abort`, via `abort` in boot/loader.s
+#[no_mangle]
+pub extern "C" fn abort_panic() -> ! {
+    panic("abort", "unknown", 0)
+}
+
+fn report(ctx: &Context, sp: u32) {
+    puts("mode ");
+    puts(mode_name(ctx.spsr));
+    puts("\n");
+    dump(ctx, sp);
+    backtrace(ctx.r[11]);
+}
+
+/// Name of the processor mode in a PSR
+pub fn mode_name(psr: u32) -> &'static str {
+    match psr & MODE_MASK {
+        0x10 => "usr",
+        0x11 => "fiq",
+        0x12 => "irq",
+        0x13 => "svc",
+        0x17 => "abt",
+        0x1B => "und",
+        0x1F => "sys",
+        _ => "???"
+    }
+}
+
+/// sp of the interrupted mode. A supervisor mode frame sits on top of the
+/// stack it was pushed to.
+pub fn saved_sp(ctx: &Context) -> u32 {
+    if ctx.spsr & MODE_MASK == MODE_USR {
+        ctx.usr_sp
+    } else {
+        ctx as *Context as u32 + size_of::<Context>() as u32
+    }
+}
+
+/// Print the registers saved in `ctx`.
+pub fn dump(ctx: &Context, sp: u32) {
+    let mut i = 0;
+    while i < 13 {
+        puts(if i < 10 { " r" } else { "r" });
+        put_dec(i as u32);
+        puts(" ");
+        put_hex(ctx.r[i]);
+        puts(if i % 4 == 3 { "\n" } else { "  " });
+        i += 1;
+    }
+    let lr = if ctx.spsr & MODE_MASK == MODE_USR { ctx.usr_lr } else { ctx.lr };
+    puts(" sp ");
+    put_hex(sp);
+    puts("   lr ");
+    put_hex(lr);
+    puts("   pc ");
+    put_hex(ctx.pc);
+    puts("\ncpsr ");
+    put_hex(ctx.spsr);
+    puts("\n");
+}
+
+/// Walk the frame pointer chain. Each frame starts with the caller's fp
+/// followed by the return address (push {r11, lr}; mov r11, sp).
+pub fn backtrace(fp: u32) {
+    puts("backtrace:\n");
+    let mut fp = fp;
+    let mut depth = 0;
+    while depth < MAX_FRAMES && fp & 3 == 0 && fp >= 0x1000 && (fp as uint) < physical::limit() {
+        let (next, lr) = unsafe { (*(fp as *u32), *((fp + 4) as *u32)) };
+        puts("  ");
+        put_hex(lr);
+        puts("\n");
+        if next <= fp {
+            break;
+        }
+        fp = next;
+        depth += 1;
+    }
+}
+
+pub fn puts(s: &str) {
+    for c in slice::iter(as_bytes(s)) {
+        putc(*c);
+    }
+}
+
+pub fn put_hex(x: u32) {
+    puts("0x");
+    let mut shift = 32;
+    while shift > 0 {
+        shift -= 4;
+        let d = ((x >> shift) & 0xF) as u8;
+        putc(if d < 10 { '0' as u8 + d } else { 'a' as u8 + d - 10 });
+    }
+}
+
+pub fn put_dec(x: u32) {
+    let mut buf = [0u8, ..10];
+    let mut n = x;
+    let mut i = 0;
+    loop {
+        buf[i] = '0' as u8 + (n % 10) as u8;
+        i += 1;
+        n /= 10;
+        if n == 0 { break; }
+    }
+    while i > 0 {
+        i -= 1;
+        putc(buf[i]);
+    }
+}
+
+fn putc(c: u8) {
+    board().putc_raw(c);
+    match board().display() {
+        Some(scr) => unsafe { scr.putChar(c) },
+        None => ()
+    }
+}
@@ -1,6 +1,10 @@
 /* kernel::serial */
 /* Serial API for UART devices */
 
+use core::option::{Some, None};
+
+use kernel::cmdline;
+
 pub mod font;
 
 //#[deriving(FromPrimative)]
@@ -73,6 +77,15 @@ pub mod Resolutions{
     pub static Pebble : Resolution = Resolution{w:144,	h:168};
 }
 
+/// Mode asked for with `video=WxH` on the command line, VGA if none
+pub fn requested() -> Resolution
+{
+    match cmdline::get_pair("video", 'x') {
+        Some((w, h)) => Resolution { w: w, h: h },
+        None => Resolutions::VGA
+    }
+}
+
 pub enum Pixel{
     NoColorPixel(),
     BWPixel(bool),
@@ -194,6 +207,27 @@ pub trait TerminalCanvas : ScreenCanvas {
         ok
     }
 
+    /// Draw a printable character at the cursor and advance it, wrapping
+    /// at the right edge. '\n' moves to the next line; other control
+    /// characters are ignored.
+    unsafe fn putChar(&mut self, c : u8)
+    {
+        let res = self.getResolution();
+        let mut cur = self.getCursor();
+        if c == '\n' as u8 {
+            cur.x = 0;
+            cur.y += cur.height;
+        } else if c >= 0x20 && c < 0x7F {
+            self.drawCharacter(c as char);
+            cur.x += cur.width;
+            if cur.x >= res.w as u32 {
+                cur.x = 0;
+                cur.y += cur.height;
+            }
+        }
+        self.setCursor(&cur);
+    }
+
 
     unsafe fn backup(&mut self);
     unsafe fn restore(&mut self);
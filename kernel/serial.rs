@@ -3,10 +3,18 @@
 /* UART communication model
  * Based on the Arduino Serial API */
 
+use core::option::{Option, Some, None};
+
+use kernel::cmdline;
+use platform::drivers::board;
+
 pub type baud = u32;
 
-// TODO One char at a time?
-pub type serialReceiveHandler<'a> = 'a|char| -> ();
+pub static DEFAULT_BAUD : baud = 9600;
+
+/// Called with each received byte, from the receive interrupt, so it
+/// must be quick and must not block
+pub type serialReceiveHandler = fn(u8);
 
 pub trait Serial{
     /// Initialize device and begin transmission.
@@ -23,18 +31,86 @@ pub trait Serial{
     fn readBuf(&mut self, buffer : &mut [u8], length : uint) -> uint;
     fn read(&mut self, c : &mut u8) -> uint;
 
-    /// Write a single character. Return number of bytes written.
-    fn write(&self, u8) -> uint;
+    /// Queue a single character. Return number of bytes written.
+    fn write(&mut self, u8) -> uint;
 
-    /// Write a buffer of bytes. Return number of bytes written.
-    fn writeBuf(&self, buffer : &[u8], length : uint) -> uint;
+    /// Queue a buffer of bytes. Return number of bytes written.
+    fn writeBuf(&mut self, buffer : &[u8], length : uint) -> uint;
 
-    fn flush(&self) -> uint;
+    /// Block until everything queued has been sent.
+    fn flush(&mut self) -> uint;
 
-    /// Callback on new data available.
-    fn addReceiveHandler(&self, serialReceiveHandler) -> bool;
+    /// Callback on new data available. Bytes still go to the read buffer,
+    /// whose oldest bytes make room once it is full. Returns a handle for
+    /// `removeReceiveHandler`, or None if the table is full.
+    fn addReceiveHandler(&mut self, serialReceiveHandler) -> Option<uint>;
+
+    fn removeReceiveHandler(&mut self, handle : uint);
 
     /// Remove all receive handlers
-    fn clearReceiveHandlers(&self) -> ();
+    fn clearReceiveHandlers(&mut self) -> ();
+}
+
+/// Rate for the console port: `baud=` from the command line, or 9600
+pub fn console_baud() -> baud
+{
+    match cmdline::get_uint("baud") {
+        Some(b) => b as baud,
+        None => DEFAULT_BAUD
+    }
 }
 
+/// Index of the port named by `console=`, e.g. `uart1`; port 0 if
+/// `console=` isn't given, None if it names none of the board's ports.
+pub fn console_port() -> Option<uint>
+{
+    match cmdline::get("console") {
+        Some(name) => {
+            let port = find_console();
+            if port.is_none() {
+                kwarn!("serial: no such console port ", name);
+            }
+            port
+        }
+        None => Some(0)
+    }
+}
+
+/// Console port, once looked up
+static mut console: Option<uint> = None;
+
+/// Index of the port kernel messages and user programs' stdio go to: the
+/// one `console=` names, falling back to port 0. Quiet and cheap, since
+/// the log calls it for every byte.
+pub fn console_index() -> uint
+{
+    unsafe {
+        match console {
+            Some(i) => i,
+            None => {
+                let i = match find_console() {
+                    Some(i) => i,
+                    None => 0
+                };
+                console = Some(i);
+                i
+            }
+        }
+    }
+}
+
+fn find_console() -> Option<uint>
+{
+    if cmdline::get("console").is_none() {
+        return Some(0);
+    }
+    let b = board();
+    let mut i = 0;
+    while i < b.serial_count() {
+        match b.serial_name(i) {
+            Some(n) if cmdline::is("console", n) => return Some(i),
+            _ => i += 1
+        }
+    }
+    None
+}
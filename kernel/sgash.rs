@@ -4,12 +4,19 @@ use core::*;
 use core::str::*;
 use core::option::{Some, Option, None}; // Match statement
 use core::iter::Iterator;
+use kernel;
 use kernel::*;
 use kernel::screen::*;
 use kernel::memory::Allocator;
 use kernel::serial::*;
 
 use kernel::shell::*;
+use kernel::device;
+use kernel::fs;
+use kernel::log;
+use kernel::task;
+use core::mem::transmute;
+use platform::cpu::interrupt;
 
 //use super::super::platform::drivers::arm926ej_s;
 //use super::super::platform::drivers::arm926ej_s::serial;
@@ -19,9 +26,89 @@ use kernel::shell::*;
 pub struct SGASH{
     buffer : cstr,
     serial : Option<&'static mut Serial>,
+    /// Our receive handler on `serial`
+    serial_handler : Option<uint>,
     screen : Option<&'static mut TerminalCanvas>,
 }
 
+/// The shell serial and keyboard input go to
+static mut attached : *mut SGASH = 0 as *mut SGASH;
+
+/// The console shell, started by `kernel::start_shell`
+pub static mut console : SGASH = SGASH {
+    buffer : cstr { p : 0 as *mut u8, p_cstr_i : 0, max : 0 },
+    serial : None,
+    serial_handler : None,
+    screen : None,
+};
+
+/// Input waiting for the shell task. Filled from interrupt handlers, which
+/// must not run the shell themselves; bytes that don't fit are dropped.
+static QUEUE_SIZE : uint = 256;
+static mut queue : [u8, ..QUEUE_SIZE] = [0, ..QUEUE_SIZE];
+static mut queue_head : uint = 0;
+static mut queue_count : uint = 0;
+
+/// How long the shell task sleeps when there is no input
+static POLL_MS : uint = 10;
+
+fn enqueue(c : u8)
+{
+    unsafe {
+        let irq = interrupt::disable();
+        if queue_count < QUEUE_SIZE {
+            queue[(queue_head + queue_count) % QUEUE_SIZE] = c;
+            queue_count += 1;
+        }
+        interrupt::restore(irq);
+    }
+}
+
+fn dequeue() -> Option<u8>
+{
+    unsafe {
+        let irq = interrupt::disable();
+        let c = if queue_count == 0 {
+            None
+        } else {
+            let c = queue[queue_head];
+            queue_head = (queue_head + 1) % QUEUE_SIZE;
+            queue_count -= 1;
+            Some(c)
+        };
+        interrupt::restore(irq);
+        c
+    }
+}
+
+/// Receive handler queueing serial input for the shell task
+fn serialInput(c : u8)
+{
+    enqueue(c);
+}
+
+/// Shell task: feed queued input to the attached shell, one byte at a time
+pub fn run()
+{
+    loop {
+        match dequeue() {
+            Some(c) => unsafe {
+                if attached as uint != 0 {
+                    (*attached).input(c as char);
+                }
+            },
+            None => task::sleep_ms(POLL_MS)
+        }
+    }
+}
+
+/// Keyboard handler, for `drivers::keydown`. Called from the keyboard
+/// interrupt, so it only queues the key like `serialInput`.
+pub fn keyInput(c : char)
+{
+    enqueue(c as u8);
+}
+
 // TODO a proper impl
 impl Shell for SGASH
 {
@@ -31,6 +118,7 @@ impl Shell for SGASH
             self.buffer = cstr::new(256);
         }
         self.serial = None;
+        self.serial_handler = None;
         self.screen = None;
     }
 
@@ -42,10 +130,18 @@ impl Shell for SGASH
             None => {
                 let mut success = true;
 
-                success = success && s.open(9600);
-                success = success && s.addReceiveHandler(|c| {
-                    self.input(c);
-                });
+                if !s.isOpen() {
+                    success = success && s.open(console_baud());
+                }
+                if success {
+                    match s.addReceiveHandler(serialInput) {
+                        Some(h) => unsafe {
+                            self.serial_handler = Some(h);
+                            attached = self as *mut SGASH;
+                        },
+                        None => success = false
+                    }
+                }
                 if(success){
                     self.serial = Some(s);
                 };
@@ -60,8 +156,9 @@ impl Shell for SGASH
         {
             Some(_) => false,
             None => {
-                self.splash();
                 self.screen = Some(s);
+                unsafe { attached = self as *mut SGASH; }
+                self.splash();
                 true
             }
         }
@@ -79,12 +176,15 @@ impl Shell for SGASH
                             self.parse();
                             self.prompt(); 
                 },
-                127		=>	unsafe { 
+                8 | 127	=>	unsafe { 
                     if (self.buffer.delete_char()) { 
                         self.txStr(&"^H ^H");
                         self.backspace();
                     }
                 },
+                // Other control characters (tab, escape, ctrl keys) do
+                // nothing yet, and the font has no glyphs for them
+                0 .. 31	=>	{ },
                 _		=>	unsafe{ 
                     if (self.buffer.add_char(c)) { 
                         //self.txChar(c as char);
@@ -124,20 +224,34 @@ impl Shell for SGASH
 
 impl SGASH
 {
-    fn txChar(&self, x : char)
+    /// Stop taking input from the serial port attached by `attachToSerial`
+    pub fn detachFromSerial(&mut self)
+    {
+        match self.serial_handler {
+            Some(h) => match self.serial {
+                Some(ref mut uart) => uart.removeReceiveHandler(h),
+                None => ()
+            },
+            None => ()
+        }
+        self.serial = None;
+        self.serial_handler = None;
+    }
+
+    fn txChar(&mut self, x : char)
     {
         match self.serial
         {
             None => (),  
-            Some(ref uart) => { uart.write(x as u8);},
+            Some(ref mut uart) => { uart.write(x as u8);},
         };
 
     }
-    fn txStr(&self, msg: &str)
+    fn txStr(&mut self, msg: &str)
     {
         match self.serial
         {
-            Some(ref uart) => for c in slice::iter(as_bytes(msg)) {
+            Some(ref mut uart) => for c in slice::iter(as_bytes(msg)) {
             	uart.write(*c);
             },
             _ => ()
@@ -146,11 +260,11 @@ impl SGASH
 
     // TODO evaluate use
     #[allow(dead_code)]
-    fn txCstr(&self, s: cstr)
+    fn txCstr(&mut self, s: cstr)
     {
         match self.serial
         {
-            Some(ref uart) => unsafe {
+            Some(ref mut uart) => unsafe {
                 let mut p = s.p as uint;
                 while *(p as *char) != '\0'
                 {
@@ -294,7 +408,31 @@ impl SGASH
         self.output(&"\n|_|_|  \\____/|_| |_|  |_|   \\_\\_____)_|   |_| |_|_____)__)\n\n");
     }
     
-    fn prompt(&mut self) 
+    /// Run each line of the file at `path` as if it were typed.
+    pub fn runScript(&mut self, path : &[u8]) -> bool
+    {
+        match fs::open(path) {
+            Some(mut file) => {
+                let mut buf = [0u8, ..64];
+                loop {
+                    let n = file.read(&mut buf);
+                    if n == 0 {
+                        break;
+                    }
+                    let mut i = 0;
+                    while i < n {
+                        // Scripts end lines with '\n'; the prompt wants Enter
+                        self.input(if buf[i] == '\n' as u8 { 13 as char } else { buf[i] as char });
+                        i += 1;
+                    }
+                }
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn prompt(&mut self) 
     {
         self.output(&"\nsgash > ");
         unsafe{ self.buffer.reset(); }
@@ -303,27 +441,67 @@ impl SGASH
     fn parse(&mut self) 
     {
         unsafe{
-            if (self.buffer.streq(&"ls")) { 
-                self.output( &"\na\tb");
-            };
             match self.buffer.getarg(' ', 0) {
                 Some(y)        => {
-                    if(y.streq(&"cat")) {
-                        match self.buffer.getarg(' ', 1) {
-                        Some(x)        => {
-                            if(x.streq(&"a")) { 
-                                self.output( &"\nHello"); 
+                    let arg = self.buffer.getarg(' ', 1);
+                    if(y.streq(&"ls")) {
+                        match arg {
+                            Some(ref x) => self.ls(x.bytes()),
+                            None        => self.ls(as_bytes("/"))
+                        }
+                    }
+                    match arg {
+                        Some(ref x)    => {
+                            if(y.streq(&"cat")) {
+                                self.cat(x.bytes());
+                            }
+                            if(y.streq(&"mkdir")) {
+                                if !fs::mkdir(x.bytes()) {
+                                    self.output(&"\nmkdir: cannot create directory");
+                                }
+                            }
+                            if(y.streq(&"touch")) {
+                                if fs::stat(x.bytes()).is_none() && fs::create(x.bytes()).is_none() {
+                                    self.output(&"\ntouch: cannot create file");
+                                }
+                            }
+                            if(y.streq(&"rm")) {
+                                if !fs::unlink(x.bytes()) {
+                                    self.output(&"\nrm: cannot remove");
+                                }
                             }
-                            if(x.streq(&"b")) {
-                                self.output( &"\nworld!");
+                            if(y.streq(&"write")) {
+                                // write <file> <text...> appends a line
+                                let file = match fs::open(x.bytes()) {
+                                    Some(f) => Some(f),
+                                    None    => fs::create(x.bytes())
+                                };
+                                match file {
+                                    Some(mut f) => {
+                                        f.seek_end();
+                                        f.write(self.buffer.rest(' ', 2));
+                                        f.write(as_bytes("\n"));
+                                    }
+                                    None    => self.output(&"\nwrite: cannot open file")
+                                }
                             }
+                            x.destroy();
                         }
                         None        => { }
-                        };
+                    };
+                    if(y.streq(&"dmesg")) {
+                        self.dmesg();
+                    }
+                    if(y.streq(&"devices")) {
+                        self.devices();
+                    }
+                    if(y.streq(&"halt")) {
+                        kernel::shutdown();
                     }
                     if(y.streq(&"open")) {
                         self.output(&"\nTEST YO");
                     }
+                    y.destroy();
                 }
                 None        => { }
             };
@@ -331,7 +509,113 @@ impl SGASH
         }
     }
 
-    fn keycode(&self, x: u8) 
+    fn ls(&mut self, path: &[u8])
+    {
+        match fs::stat(path) {
+            Some(st) => {
+                match st.kind {
+                    fs::Directory => (),
+                    _ => {
+                        self.output(&"\n");
+                        self.outputBytes(path);
+                        return;
+                    }
+                }
+                let mut i = 0;
+                loop {
+                    match fs::readdir(path, i) {
+                        Some(entry) => {
+                            self.output(&"\n");
+                            self.outputBytes(entry.name());
+                            match entry.kind {
+                                fs::Directory => self.output(&"/"),
+                                _ => ()
+                            }
+                        }
+                        None => break
+                    }
+                    i += 1;
+                }
+            }
+            None => self.output(&"\nls: no such file or directory")
+        }
+    }
+
+    fn cat(&mut self, path: &[u8])
+    {
+        match fs::open(path) {
+            Some(mut file) => {
+                let mut buf = [0u8, ..64];
+                self.output(&"\n");
+                loop {
+                    let n = file.read(&mut buf);
+                    if n == 0 {
+                        break;
+                    }
+                    self.outputBytes(unsafe { transmute((&buf[0] as *u8, n)) });
+                }
+            }
+            None => self.output(&"\ncat: no such file")
+        }
+    }
+
+    fn dmesg(&mut self)
+    {
+        self.output(&"\n");
+        log::dump(|b| self.outputBytes(b));
+    }
+
+    /// One line per registered driver: name, compatible, base,
+    /// capabilities and whether it bound
+    fn devices(&mut self)
+    {
+        let mut i = 0;
+        loop {
+            match device::get(i) {
+                Some((d, state)) => {
+                    self.output(&"\n");
+                    self.output(d.name);
+                    self.output(&"  ");
+                    self.output(d.compatible);
+                    self.output(&" @ ");
+                    self.outputHex(d.base);
+                    if d.caps & device::SERIAL != 0 { self.output(&" serial"); }
+                    if d.caps & device::CANVAS != 0 { self.output(&" canvas"); }
+                    if d.caps & device::BLOCK != 0 { self.output(&" block"); }
+                    if d.caps & device::INPUT != 0 { self.output(&" input"); }
+                    self.output(match state {
+                        device::Bound   => &" [bound]",
+                        device::Failed  => &" [failed]",
+                        device::Absent  => &" [absent]",
+                        device::Pending => &" [pending]"
+                    });
+                }
+                None => break
+            }
+            i += 1;
+        }
+    }
+
+    fn outputHex(&mut self, x: u32)
+    {
+        let digits = as_bytes("0123456789abcdef");
+        let mut buf = [0u8, ..10];
+        buf[0] = '0' as u8;
+        buf[1] = 'x' as u8;
+        let mut i = 0u;
+        while i < 8 {
+            buf[9 - i] = digits[((x >> (4 * i)) & 0xF) as uint];
+            i += 1;
+        }
+        self.outputBytes(&buf);
+    }
+
+    fn outputBytes(&mut self, b: &[u8])
+    {
+        self.output(unsafe { transmute(b) });
+    }
+
+    fn keycode(&mut self, x: u8) 
     {
         let mut x = x;
         while  x != 0 {
@@ -378,6 +662,20 @@ impl cstr {
 	#[allow(dead_code)]
     fn len(&self) -> uint { self.p_cstr_i }
 
+	fn bytes<'a>(&'a self) -> &'a [u8] {
+		unsafe { transmute((self.p as *u8, self.p_cstr_i)) }
+	}
+
+	/// Everything from the `k`th `delim`-separated argument to the end
+	unsafe fn rest<'a>(&'a self, delim: char, mut k: uint) -> &'a [u8] {
+		let mut i = 0;
+		while k > 0 && i < self.p_cstr_i {
+			if *(((self.p as uint) + i) as *u8) == delim as u8 { k -= 1; }
+			i += 1;
+		}
+		transmute(((self.p as uint + i) as *u8, self.p_cstr_i - i))
+	}
+
 	// HELP THIS DOESN'T WORK THERE IS NO GARBAGE COLLECTION!!!
 	// -- TODO: exchange_malloc, exchange_free
     #[allow(dead_code)]
@@ -0,0 +1,182 @@
+/* kernel::syscall */
+/* System calls, entered with `swi`. The call number is the swi immediate,
+ * or r7 when the immediate is zero (EABI style). Arguments are passed in
+ * r0-r3 and the result is returned in r0; -1 means failure. */
+
+use core::option::{Option, Some, None};
+use core::mem::transmute;
+
+use kernel::serial::Serial;
+use kernel::task;
+use kernel::time;
+use platform::cpu::interrupt::Context;
+use platform::drivers::board;
+
+pub static SYS_WRITE : u32 = 1;
+pub static SYS_READ  : u32 = 2;
+pub static SYS_YIELD : u32 = 3;
+pub static SYS_EXIT  : u32 = 4;
+pub static SYS_SLEEP : u32 = 5;
+pub static SYS_SBRK  : u32 = 6;
+
+static NR_SYSCALLS : uint = 7;
+static ERROR : u32 = -1 as u32;
+
+/// Mode bits of the caller's saved CPSR
+static MODE_MASK : u32 = 0x1F;
+static MODE_USR  : u32 = 0x10;
+
+/// Console file descriptors
+static STDIN  : u32 = 0;
+static STDOUT : u32 = 1;
+static STDERR : u32 = 2;
+
+type Syscall = unsafe fn(&mut Context) -> u32;
+
+static TABLE : [Option<Syscall>, ..NR_SYSCALLS] = [
+    None,
+    Some(sys_write),
+    Some(sys_read),
+    Some(sys_yield),
+    Some(sys_exit),
+    Some(sys_sleep),
+    Some(sys_sbrk),
+];
+
+/// Called from `swi_entry` with the caller's saved context. Returns the
+/// context to resume, which is another task's if the call blocked.
+#[no_mangle]
+pub unsafe extern "C" fn swi_dispatch(ctx : *mut Context) -> *mut Context
+{
+    let insn = *(((*ctx).pc - 4) as *u32);
+    let nr = match insn & 0xFFFFFF {
+        0 => (*ctx).r[7],
+        imm => imm
+    };
+    (*ctx).r[0] = if (nr as uint) < NR_SYSCALLS {
+        match TABLE[nr] {
+            Some(call) => call(&mut *ctx),
+            None => ERROR
+        }
+    } else {
+        ERROR
+    };
+    task::preempt(ctx)
+}
+
+/// The bytes [ptr, ptr + len) as a slice, if the caller may touch them.
+/// A caller in user mode (going by its saved `spsr`) must have them mapped
+/// in its user region, writable if `write` is set since the kernel is
+/// about to store into them.
+unsafe fn user_buffer(spsr : u32, ptr : u32, len : u32, write : bool) -> Option<&'static mut [u8]>
+{
+    if ptr == 0 || ptr + len < ptr {
+        return None;
+    }
+    if spsr & MODE_MASK == MODE_USR && !task::space().user_accessible(ptr, len, write) {
+        return None;
+    }
+    Some(transmute((ptr as *mut u8, len as uint)))
+}
+
+/// write(fd, buf, len) -> bytes written
+unsafe fn sys_write(ctx : &mut Context) -> u32
+{
+    let (fd, ptr, len) = (ctx.r[0], ctx.r[1], ctx.r[2]);
+    if fd != STDOUT && fd != STDERR {
+        return ERROR;
+    }
+    match user_buffer(ctx.spsr, ptr, len, false) {
+        Some(buf) => board().console().writeBuf(buf, len as uint) as u32,
+        None => ERROR
+    }
+}
+
+/// read(fd, buf, len) -> bytes read; never blocks
+unsafe fn sys_read(ctx : &mut Context) -> u32
+{
+    let (fd, ptr, len) = (ctx.r[0], ctx.r[1], ctx.r[2]);
+    if fd != STDIN {
+        return ERROR;
+    }
+    match user_buffer(ctx.spsr, ptr, len, true) {
+        Some(buf) => board().console().readBuf(buf, len as uint) as u32,
+        None => ERROR
+    }
+}
+
+/// yield()
+unsafe fn sys_yield(_ : &mut Context) -> u32
+{
+    task::request_resched();
+    0
+}
+
+/// exit(code)
+unsafe fn sys_exit(_ : &mut Context) -> u32
+{
+    if task::mark_exit() { 0 } else { ERROR }
+}
+
+/// sleep(ms)
+unsafe fn sys_sleep(ctx : &mut Context) -> u32
+{
+    let at = time::ticks() + time::ms_to_ticks(ctx.r[0] as uint);
+    if task::block_until(at) { 0 } else { ERROR }
+}
+
+/// sbrk(increment) -> previous break, in the caller's user region
+unsafe fn sys_sbrk(ctx : &mut Context) -> u32
+{
+    match task::sbrk(ctx.r[0] as i32 as int) {
+        Some(p) => p as u32,
+        None => ERROR
+    }
+}
+
+/// Wrappers for making the calls above
+pub mod user
+{
+    #[inline(never)]
+    pub fn syscall(nr : u32, a : u32, b : u32, c : u32) -> u32
+    {
+        let ret : u32;
+        unsafe {
+            asm!("swi 0"
+                : "={r0}"(ret)
+                : "{r7}"(nr), "{r0}"(a), "{r1}"(b), "{r2}"(c)
+                : "lr", "memory");
+        }
+        ret
+    }
+
+    pub fn write(fd : u32, buf : &[u8], len : uint) -> u32
+    {
+        syscall(super::SYS_WRITE, fd, &buf[0] as *u8 as u32, len as u32)
+    }
+
+    pub fn read(fd : u32, buf : &mut [u8], len : uint) -> u32
+    {
+        syscall(super::SYS_READ, fd, &mut buf[0] as *mut u8 as u32, len as u32)
+    }
+
+    pub fn yield_now()
+    {
+        syscall(super::SYS_YIELD, 0, 0, 0);
+    }
+
+    pub fn exit(code : u32)
+    {
+        syscall(super::SYS_EXIT, code, 0, 0);
+    }
+
+    pub fn sleep(ms : u32)
+    {
+        syscall(super::SYS_SLEEP, ms, 0, 0);
+    }
+
+    pub fn sbrk(incr : int) -> *mut u8
+    {
+        syscall(super::SYS_SBRK, incr as u32, 0, 0) as *mut u8
+    }
+}
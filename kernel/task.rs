@@ -0,0 +1,303 @@
+/* kernel::task */
+/* Preemptive round-robin scheduling of kernel tasks */
+
+use core::mem::{size_of, transmute};
+use core::option::{Option, Some, None};
+
+use kernel;
+use kernel::memory::physical;
+use kernel::memory::virtual::{AddressSpace, PAGE_SIZE};
+use kernel::time;
+use platform::cpu::interrupt;
+use platform::cpu::interrupt::Context;
+
+/// Kernel stack per task, in 4 KiB frames
+static STACK_FRAMES : uint = 2;
+static STACK_SIZE : uint = STACK_FRAMES << 12;
+/// Ticks a task may run before it is preempted
+static QUANTUM : uint = 5;
+/// Supervisor mode, IRQs enabled
+static TASK_CPSR : u32 = 0x13;
+/// Where `sbrk` memory starts in a task's user region, and how far it
+/// may grow
+static BRK_BASE : u32 = 0x60000000;
+static BRK_LIMIT : u32 = 0x70000000;
+
+pub enum State {
+    Runnable,
+    /// Waiting for the tick count to reach the given value
+    Sleeping(u64),
+    Dead
+}
+
+pub struct Task {
+    id      : uint,
+    state   : State,
+    /// Saved registers while the task is not running
+    context : *mut Context,
+    /// Base of the kernel stack, null for the boot task
+    stack   : *mut u8,
+    /// Page directory in use while the task runs, owned by the task
+    space   : AddressSpace,
+    /// Program break: bytes in use from BRK_BASE, and bytes backed by pages
+    brk      : uint,
+    brk_mapped : uint,
+    /// Next task in the run queue, which is a ring
+    next    : *mut Task,
+}
+
+static mut current : *mut Task = 0 as *mut Task;
+static mut next_id : uint = 0;
+static mut slice_left : uint = QUANTUM;
+static mut need_resched : bool = false;
+
+extern {
+    fn task_yield();
+}
+
+/// Turn the running boot thread into task 0. It never exits, so there is
+/// always something to run.
+pub fn init()
+{
+    unsafe {
+        let t = kernel::zero_alloc(size_of::<Task>()) as *mut Task;
+        (*t).id = 0;
+        (*t).state = Runnable;
+        (*t).space = AddressSpace::kernel();
+        (*t).next = t;
+        current = t;
+        next_id = 1;
+    }
+}
+
+/// Start a new task running `entry`. Returns its id.
+pub fn spawn(entry : fn()) -> uint
+{
+    spawn_in(entry, AddressSpace::kernel())
+}
+
+/// Start a new task running `entry` in `space`, which the task then owns.
+pub fn spawn_in(entry : fn(), space : AddressSpace) -> uint
+{
+    unsafe {
+        let t = kernel::zero_alloc(size_of::<Task>()) as *mut Task;
+        let stack = physical::alloc_frames(STACK_FRAMES);
+        let ctx = (stack as uint + STACK_SIZE - size_of::<Context>()) as *mut Context;
+
+        (*ctx).usr_sp = 0;
+        (*ctx).usr_lr = 0;
+        (*ctx).spsr = TASK_CPSR;
+        (*ctx).r = [0, ..13];
+        (*ctx).r[0] = transmute(entry);
+        (*ctx).lr = 0;
+        (*ctx).pc = transmute(task_start);
+
+        (*t).state = Runnable;
+        (*t).context = ctx;
+        (*t).stack = stack;
+        (*t).space = space;
+
+        let irq = interrupt::disable();
+        (*t).id = next_id;
+        next_id += 1;
+        (*t).next = (*current).next;
+        (*current).next = t;
+        interrupt::restore(irq);
+
+        (*t).id
+    }
+}
+
+/// Give up the CPU to the next runnable task.
+pub fn yield_now()
+{
+    unsafe { task_yield(); }
+}
+
+/// End the calling task. Its stack is reclaimed once another task runs.
+pub fn exit() -> !
+{
+    unsafe {
+        interrupt::disable();
+        (*current).state = Dead;
+        task_yield();
+    }
+    loop {}
+}
+
+/// Stop running the current task until tick `at`. Takes effect at the
+/// next switch; returns false for the boot task, which may not block.
+pub fn block_until(at : u64) -> bool
+{
+    unsafe {
+        if (*current).id == 0 {
+            return false;
+        }
+        (*current).state = Sleeping(at);
+        need_resched = true;
+    }
+    true
+}
+
+/// Mark the current task dead; it goes away at the next switch.
+pub fn mark_exit() -> bool
+{
+    unsafe {
+        if (*current).id == 0 {
+            return false;
+        }
+        (*current).state = Dead;
+        need_resched = true;
+    }
+    true
+}
+
+/// Switch away at the next opportunity.
+pub fn request_resched()
+{
+    unsafe { need_resched = true; }
+}
+
+pub fn sleep_ms(ms : uint)
+{
+    let at = time::ticks() + time::ms_to_ticks(ms);
+    if block_until(at) {
+        yield_now();
+    } else {
+        while time::ticks() < at {
+            yield_now();
+        }
+    }
+}
+
+/// Move the current task's program break by `incr` bytes, returning the
+/// old break. The memory is mapped into the task's user region, so tasks
+/// in the kernel's address space have none.
+pub fn sbrk(incr : int) -> Option<*mut u8>
+{
+    unsafe {
+        let t = current;
+        if (*t).space.is_kernel() {
+            return None;
+        }
+        let old = (*t).brk as int;
+        let new = old + incr;
+        if new < 0 || new > (BRK_LIMIT - BRK_BASE) as int {
+            return None;
+        }
+        // Pages stay mapped when the break shrinks; they go with the space
+        let page = PAGE_SIZE as uint;
+        let end = (new as uint + page - 1) & !(page - 1);
+        if end > (*t).brk_mapped {
+            let at = BRK_BASE + (*t).brk_mapped as u32;
+            if !(*t).space.alloc(at, (end - (*t).brk_mapped) / page, true) {
+                return None;
+            }
+            (*t).brk_mapped = end;
+        }
+        (*t).brk = new as uint;
+        Some((BRK_BASE as uint + old as uint) as *mut u8)
+    }
+}
+
+/// Address space of the running task
+pub fn space() -> &'static mut AddressSpace
+{
+    unsafe { &mut (*current).space }
+}
+
+pub fn current_id() -> uint
+{
+    unsafe { (*current).id }
+}
+
+/// Timer tick: charge the running task and ask for a switch when its
+/// quantum runs out.
+pub fn tick()
+{
+    unsafe {
+        if slice_left > 0 {
+            slice_left -= 1;
+        }
+        if slice_left == 0 {
+            need_resched = true;
+        }
+    }
+}
+
+/// Called on the way out of the IRQ handler. Returns the context to resume.
+pub unsafe fn preempt(ctx : *mut Context) -> *mut Context
+{
+    if need_resched && current as uint != 0 {
+        switch(ctx)
+    } else {
+        ctx
+    }
+}
+
+/// Kill the running task from an exception handler. Returns the context
+/// of the task to run instead.
+pub unsafe fn kill_current(ctx : *mut Context) -> *mut Context
+{
+    (*current).state = Dead;
+    switch(ctx)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn task_yield_switch(ctx : *mut Context) -> *mut Context
+{
+    switch(ctx)
+}
+
+/// Save `ctx` as the running task's context and pick the next runnable
+/// task, freeing dead ones along the way. Must run with IRQs masked.
+unsafe fn switch(ctx : *mut Context) -> *mut Context
+{
+    let prev = current;
+    (*prev).context = ctx;
+
+    let mut p = prev;
+    loop {
+        let t = (*p).next;
+        match (*t).state {
+            Dead if t != prev => {
+                (*p).next = (*t).next;
+                release(t);
+                continue;
+            }
+            Sleeping(at) if time::ticks() >= at => {
+                (*t).state = Runnable;
+                current = t;
+                break;
+            }
+            Runnable => {
+                current = t;
+                break;
+            }
+            _ => p = t
+        }
+    }
+
+    if !(*current).space.same_as(&(*prev).space) {
+        (*current).space.activate();
+    }
+
+    slice_left = QUANTUM;
+    need_resched = false;
+    (*current).context
+}
+
+unsafe fn release(t : *mut Task)
+{
+    if (*t).stack as uint != 0 {
+        physical::free_frames((*t).stack);
+    }
+    (*t).space.destroy();
+    kernel::free(t as *mut u8);
+}
+
+extern "C" fn task_start(entry : fn()) -> !
+{
+    entry();
+    exit()
+}
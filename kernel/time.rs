@@ -0,0 +1,131 @@
+/* kernel::time */
+/* Monotonic system time, counted in timer ticks */
+
+use core::option::{Option, Some, None};
+use core::mem::volatile_load;
+
+use platform::cpu::interrupt;
+use platform::drivers::board;
+use kernel::task;
+
+/// Tick frequency
+pub static HZ : u32 = 100;
+
+static MAX_CALLBACKS : uint = 16;
+
+static mut jiffies : u64 = 0;
+static mut callbacks : [Option<(u64, fn())>, ..MAX_CALLBACKS] = [None, ..MAX_CALLBACKS];
+
+/// Start the periodic tick. Until this is called time stands still.
+pub fn init() -> bool
+{
+    let ok = board().timer_start(HZ, tick);
+    if ok {
+        kinfo!("time: ", HZ, " Hz tick");
+    } else {
+        kerror!("time: no tick source");
+    }
+    ok
+}
+
+/// Ticks since `init`
+pub fn ticks() -> u64
+{
+    unsafe {
+        // A 64-bit load is two loads; retry if a tick lands in between
+        loop {
+            let a = volatile_load(&jiffies as *u64);
+            let b = volatile_load(&jiffies as *u64);
+            if a == b {
+                return a;
+            }
+        }
+    }
+}
+
+/// Milliseconds since `init`
+pub fn uptime_ms() -> u64
+{
+    // The tick count and the time since it must come from the same tick;
+    // the timer owns up to a tick still pending behind the mask
+    let irq = interrupt::disable();
+    let ms = ticks() * 1000 / HZ as u64 + (board().timer_elapsed_us() / 1000) as u64;
+    interrupt::restore(irq);
+    ms
+}
+
+/// Smallest number of ticks covering at least `ms` milliseconds
+pub fn ms_to_ticks(ms : uint) -> u64
+{
+    (ms as u64 * HZ as u64 + 999) / 1000
+}
+
+/// A point in time to poll against, e.g. for timeouts.
+pub struct Deadline {
+    at : u64
+}
+
+impl Deadline
+{
+    pub fn after_ms(ms : uint) -> Deadline
+    {
+        Deadline { at: ticks() + ms_to_ticks(ms) }
+    }
+
+    pub fn expired(&self) -> bool
+    {
+        ticks() >= self.at
+    }
+}
+
+/// Call `f` once from the timer interrupt after `ms` milliseconds.
+/// Returns a handle for `cancel`, or None if all slots are taken.
+pub fn call_after(ms : uint, f : fn()) -> Option<uint>
+{
+    let at = ticks() + ms_to_ticks(ms);
+    let mut i = 0;
+    let irq = interrupt::disable();
+    unsafe {
+        while i < MAX_CALLBACKS {
+            match callbacks[i] {
+                None => {
+                    callbacks[i] = Some((at, f));
+                    interrupt::restore(irq);
+                    return Some(i);
+                }
+                Some(_) => i += 1
+            }
+        }
+    }
+    interrupt::restore(irq);
+    None
+}
+
+pub fn cancel(handle : uint)
+{
+    if handle < MAX_CALLBACKS {
+        let irq = interrupt::disable();
+        unsafe { callbacks[handle] = None; }
+        interrupt::restore(irq);
+    }
+}
+
+/// Timer interrupt handler
+fn tick()
+{
+    unsafe {
+        jiffies += 1;
+        task::tick();
+        let mut i = 0;
+        while i < MAX_CALLBACKS {
+            match callbacks[i] {
+                Some((at, f)) if at <= jiffies => {
+                    callbacks[i] = None;
+                    f();
+                }
+                _ => ()
+            }
+            i += 1;
+        }
+    }
+}
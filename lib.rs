@@ -16,6 +16,7 @@ pub use support::{memcpy, memmove};
 use platform::{cpu};
 //use platform::{io, drivers};
 
+#[macro_escape]
 pub mod kernel;
 
 #[cfg(target_arch = "arm")]